@@ -0,0 +1,57 @@
+//! Compares the tree interpreter with the bytecode engine on every program in
+//! `benches/programs`. Brainfuck programs end in `.b` or `.bf`, LIL programs in `.lil`.
+//! `mandelbrot.b` and `hanoi.b` are the heavy ones; they were compiled from LIL, so they
+//! spend their time in the same copy and counting loops as real compiler output.
+
+use std::fs;
+use std::io::{empty, sink};
+use std::path::Path;
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+use brainfuck_compiler::{BrainFuckInterpreter, BrainFuckProgram, Bytecode, DesugaredBrainFuckProgram, LowLevelIntermediateProgram};
+
+fn load(path: &Path) -> Option<DesugaredBrainFuckProgram> {
+    let source = fs::read_to_string(path).ok()?;
+    match path.extension()?.to_str()? {
+        "b" | "bf" => source.parse::<BrainFuckProgram>().ok()?.desugar().ok(),
+        "lil" => Some(LowLevelIntermediateProgram::parse(&source).ok()?.compile()),
+        _ => None,
+    }
+}
+
+fn programs() -> Vec<(String, DesugaredBrainFuckProgram)> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/programs");
+    let mut res: Vec<_> = fs::read_dir(dir)
+        .expect("couldn't read benches/programs")
+        .filter_map(|entry| {
+            let path = entry.ok()?.path();
+            let name = path.file_stem()?.to_str()?.to_string();
+            Some((name, load(&path)?))
+        })
+        .collect();
+    res.sort_by(|a, b| a.0.cmp(&b.0));
+    res
+}
+
+fn engines(c: &mut Criterion) {
+    for (name, program) in programs() {
+        let mut group = c.benchmark_group(&name);
+        group.sample_size(10);
+
+        let bytecode = Bytecode::compile(&program);
+        group.bench_function("bytecode", |b| b.iter(|| {
+            BrainFuckInterpreter::new(sink(), empty()).execute_bytecode(&bytecode).unwrap()
+        }));
+
+        // the tree interpreter takes the program by value
+        group.bench_function("tree", |b| b.iter_batched(
+            || program.clone(),
+            |program| BrainFuckInterpreter::new(sink(), empty()).execute(program).unwrap(),
+            BatchSize::LargeInput,
+        ));
+
+        group.finish();
+    }
+}
+
+criterion_group!(benches, engines);
+criterion_main!(benches);
@@ -0,0 +1,9 @@
+prints the alphabet with a busy loop of a hundred thousand iterations before every letter
+
+>+++++[<+++++>-]<+              26 letters
+>>++++++++[<++++++++>-]<+<      starting at A
+[
+    >>++++++++++[>++++++++++[>++++++++++[>++++++++++[>++++++++++[->+<]<-]<-]<-]<-]
+    <.+<-
+]
+++++++++++.
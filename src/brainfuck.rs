@@ -1,8 +1,9 @@
 use std::convert::Infallible;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
-use crate::brainfuck::UnbalancedLoop::{OpenWithoutClose, TooManyClose};
-use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram};
+use crate::cell::CellWidth;
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, SourceEntry, SourceMap};
+use crate::source::{Snippet, SourcePosition, Span};
 
 #[derive(Clone, PartialEq, Copy, Debug)]
 pub enum BrainFuckInstruction {
@@ -18,19 +19,18 @@ pub enum BrainFuckInstruction {
 
 impl BrainFuckInstruction {
     pub fn set(n: u8) -> Vec<Self> {
-        let mut res = Vec::new();
-        res.push(BrainFuckInstruction::LoopStart);
-        res.push(BrainFuckInstruction::Sub);
-        res.push(BrainFuckInstruction::LoopEnd);
-        if n > 128 {
-            res.push(BrainFuckInstruction::Sub);
-            for _ in n..255 {
-                res.push(BrainFuckInstruction::Sub);
-            }
+        Self::set_for(n as u32, CellWidth::U8)
+    }
+
+    /// Sets a cell of the given width to `n`, going down from zero when that's shorter.
+    pub fn set_for(n: u32, width: CellWidth) -> Vec<Self> {
+        let mut res = vec![BrainFuckInstruction::LoopStart, BrainFuckInstruction::Sub, BrainFuckInstruction::LoopEnd];
+        let half = 1 << (width.bits() - 1);
+        if n > half {
+            let subs = width.max_value() - n + 1;
+            res.resize(res.len() + subs as usize, BrainFuckInstruction::Sub);
         } else {
-            for _ in 0..n {
-                res.push(BrainFuckInstruction::Add);
-            }
+            res.resize(res.len() + n as usize, BrainFuckInstruction::Add);
         }
 
         res
@@ -49,119 +49,255 @@ impl BrainFuckInstruction {
             _ => None,
         }
     }
+
+    pub fn to_char(self) -> char {
+        match self {
+            Self::Add => '+',
+            Self::Sub => '-',
+            Self::Right => '>',
+            Self::Left => '<',
+            Self::LoopStart => '[',
+            Self::LoopEnd => ']',
+            Self::Input => ',',
+            Self::Output => '.',
+        }
+    }
 }
 
-pub struct BrainFuckProgram(Vec<BrainFuckInstruction>);
+pub struct BrainFuckProgram {
+    instructions: Vec<BrainFuckInstruction>,
+    // empty when the program wasn't parsed from source
+    positions: Vec<SourcePosition>,
+    source: Option<String>,
+}
 
-pub enum UnbalancedLoop {
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum UnbalancedLoopKind {
     TooManyClose,
     OpenWithoutClose,
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnbalancedLoop {
+    pub kind: UnbalancedLoopKind,
+    /// the stray `]` or the `[` that is never closed
+    pub position: Option<SourcePosition>,
+    snippet: Option<Snippet>,
+}
+
+impl Display for UnbalancedLoop {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            UnbalancedLoopKind::TooManyClose => write!(f, "unbalanced loop: ']' without matching '['")?,
+            UnbalancedLoopKind::OpenWithoutClose => write!(f, "unbalanced loop: '[' without matching ']'")?,
+        }
+        if let Some(position) = self.position {
+            write!(f, " at {position}")?;
+        }
+        if let Some(snippet) = &self.snippet {
+            write!(f, "\n{snippet}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnbalancedLoop {}
+
+/// Every unbalanced loop in a program, in source order.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnbalancedLoops(Vec<UnbalancedLoop>);
+
+impl UnbalancedLoops {
+    pub fn errors(&self) -> &[UnbalancedLoop] {
+        &self.0
+    }
+}
+
+impl Display for UnbalancedLoops {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (idx, i) in self.0.iter().enumerate() {
+            if idx != 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{i}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnbalancedLoops {}
+
 impl BrainFuckProgram {
     pub fn from_instructions(v: impl AsRef<[BrainFuckInstruction]>) -> Self {
-        Self(v.as_ref().to_vec())
+        Self {
+            instructions: v.as_ref().to_vec(),
+            positions: Vec::new(),
+            source: None,
+        }
     }
 
-    fn desugar_iter<'a>(inp: impl Iterator<Item=&'a BrainFuckInstruction>) -> Result<Vec<DesugaredBrainFuckInstruction>, UnbalancedLoop> {
-        let mut iter = inp.peekable();
+    pub fn instructions(&self) -> &[BrainFuckInstruction] {
+        &self.instructions
+    }
 
-        let mut res = Vec::new();
-        while let Some(i) = iter.next() {
+    /// Where the instruction at `idx` was in the source, if the program was parsed from source.
+    pub fn position(&self, idx: usize) -> Option<SourcePosition> {
+        self.positions.get(idx).copied()
+    }
+
+    fn unbalanced_loop(&self, kind: UnbalancedLoopKind, idx: usize) -> UnbalancedLoop {
+        let position = self.position(idx);
+        UnbalancedLoop {
+            kind,
+            position,
+            snippet: self.source.as_ref().zip(position).map(|(source, position)| Snippet::new(source, position, 1)),
+        }
+    }
+
+    fn check_loops(&self) -> Result<(), UnbalancedLoops> {
+        let mut errors = Vec::new();
+        let mut open = Vec::new();
+
+        for (idx, i) in self.instructions.iter().enumerate() {
             match i {
-                BrainFuckInstruction::Add => {
-                    let mut num = 1;
-                    while let Some(BrainFuckInstruction::Add) = iter.peek() {
-                        let _ = iter.next();
-                        num += 1;
-                    }
-                    res.push(DesugaredBrainFuckInstruction::Add(num))
-                },
-                BrainFuckInstruction::Sub => {
-                    let mut num = 1;
-                    while let Some(BrainFuckInstruction::Sub) = iter.peek() {
-                        let _ = iter.next();
-                        num += 1;
-                    }
-                    res.push(DesugaredBrainFuckInstruction::Sub(num))
-                },
-                BrainFuckInstruction::Left => {
-                    let mut num = 1;
-                    while let Some(BrainFuckInstruction::Left) = iter.peek() {
-                        let _ = iter.next();
-                        num += 1;
+                BrainFuckInstruction::LoopStart => open.push(idx),
+                BrainFuckInstruction::LoopEnd if open.pop().is_none() => {
+                    errors.push((idx, UnbalancedLoopKind::TooManyClose));
+                }
+                _ => {}
+            }
+        }
+        errors.extend(open.into_iter().map(|idx| (idx, UnbalancedLoopKind::OpenWithoutClose)));
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            errors.sort_by_key(|(idx, _)| *idx);
+            Err(UnbalancedLoops(errors.into_iter()
+                .map(|(idx, kind)| self.unbalanced_loop(kind, idx))
+                .collect()))
+        }
+    }
+
+    // expects balanced loops, see `check_loops`
+    // desugars `instructions[start..end]`, recording which instructions every result came from
+    fn desugar_range(&self, start: usize, end: usize) -> (Vec<DesugaredBrainFuckInstruction>, Vec<Origin>) {
+        let instructions = &self.instructions;
+        let mut res = Vec::new();
+        let mut origins: Vec<Origin> = Vec::new();
+
+        let mut idx = start;
+        while idx < end {
+            let first = idx;
+            let i = instructions[idx];
+            idx += 1;
+
+            let desugared = match i {
+                BrainFuckInstruction::Add | BrainFuckInstruction::Sub | BrainFuckInstruction::Left | BrainFuckInstruction::Right => {
+                    while idx < end && instructions[idx] == i {
+                        idx += 1;
                     }
-                    res.push(DesugaredBrainFuckInstruction::Left(num))
-                },
-                BrainFuckInstruction::Right => {
-                    let mut num = 1;
-                    while let Some(BrainFuckInstruction::Right) = iter.peek() {
-                        let _ = iter.next();
-                        num += 1;
+                    let num = idx - first;
+                    match i {
+                        BrainFuckInstruction::Add => DesugaredBrainFuckInstruction::Add(num),
+                        BrainFuckInstruction::Sub => DesugaredBrainFuckInstruction::Sub(num),
+                        BrainFuckInstruction::Left => DesugaredBrainFuckInstruction::Left(num),
+                        _ => DesugaredBrainFuckInstruction::Right(num),
                     }
-                    res.push(DesugaredBrainFuckInstruction::Right(num))
-                },
+                }
                 BrainFuckInstruction::LoopStart => {
-                    let mut loop_part = Vec::new();
-                    let mut ctr = 0;
-                    loop {
-                        if let Some(nxt) = iter.next() {
-                            if nxt == &BrainFuckInstruction::LoopEnd {
-                                if ctr == 0 {
-                                    break
-                                } else {
-                                    ctr -= 1;
-                                    loop_part.push(nxt)
-                                }
-                            } else {
-                                if nxt == &BrainFuckInstruction::LoopStart {
-                                    ctr += 1;
-                                }
-                                loop_part.push(nxt)
+                    let mut depth = 0;
+                    let close = (idx..end)
+                        .find(|&j| match instructions[j] {
+                            BrainFuckInstruction::LoopStart => {
+                                depth += 1;
+                                false
                             }
-                        } else {
-                            return Err(OpenWithoutClose)
-                        }
-                    }
+                            BrainFuckInstruction::LoopEnd if depth == 0 => true,
+                            BrainFuckInstruction::LoopEnd => {
+                                depth -= 1;
+                                false
+                            }
+                            _ => false,
+                        })
+                        .expect("loops are checked before desugaring");
+                    idx = close + 1;
 
-                    if loop_part == vec![&BrainFuckInstruction::Sub] || loop_part == vec![&BrainFuckInstruction::Add] {
-                        if res.last() != Some(&DesugaredBrainFuckInstruction::Zero) {
-                            res.push(DesugaredBrainFuckInstruction::Zero)
+                    let body = &instructions[first + 1..close];
+                    if body == [BrainFuckInstruction::Sub] || body == [BrainFuckInstruction::Add] {
+                        if res.last() == Some(&DesugaredBrainFuckInstruction::Zero) {
+                            // a second clear does nothing, it becomes part of the first
+                            if let Some(i) = origins.last_mut() {
+                                i.last = close;
+                            }
+                            continue;
                         }
+                        DesugaredBrainFuckInstruction::Zero
                     } else {
-                        res.push(DesugaredBrainFuckInstruction::Loop(Self::desugar_iter(loop_part.into_iter())?))
+                        let (body, body_origins) = self.desugar_range(first + 1, close);
+                        res.push(DesugaredBrainFuckInstruction::Loop(body));
+                        origins.push(Origin { first, last: first, end: Some(close), body: body_origins });
+                        continue;
                     }
-                },
+                }
                 BrainFuckInstruction::LoopEnd => {
-                    return Err(TooManyClose);
+                    unreachable!("loops are checked before desugaring")
                 }
-                BrainFuckInstruction::Input => res.push(DesugaredBrainFuckInstruction::Input),
-                BrainFuckInstruction::Output => res.push(DesugaredBrainFuckInstruction::Output),
-            }
+                BrainFuckInstruction::Input => DesugaredBrainFuckInstruction::Input,
+                BrainFuckInstruction::Output => DesugaredBrainFuckInstruction::Output,
+            };
+            res.push(desugared);
+            origins.push(Origin { first, last: idx - 1, end: None, body: Vec::new() });
         }
 
-        Ok(res)
+        (res, origins)
     }
 
+    // the source code of the instructions `first..=last`
+    fn span(&self, first: usize, last: usize) -> Span {
+        let end = self.positions[last].advance(self.instructions[last].to_char());
+        Span::new(self.positions[first], end)
+    }
+
+    fn source_entries(&self, origins: Vec<Origin>) -> Vec<SourceEntry> {
+        origins.into_iter()
+            .map(|i| SourceEntry {
+                span: self.span(i.first, i.last),
+                end: i.end.map(|end| self.span(end, end)),
+                body: self.source_entries(i.body),
+            })
+            .collect()
+    }
+
+    /// Desugars the program. Programs parsed from source get a [`SourceMap`] that points back
+    /// into it.
+    pub fn desugar(&self) -> Result<DesugaredBrainFuckProgram, UnbalancedLoops> {
+        self.check_loops()?;
+        let (instructions, origins) = self.desugar_range(0, self.instructions.len());
+        let program = DesugaredBrainFuckProgram::from_instructions(instructions);
+        if self.positions.is_empty() {
+            return Ok(program);
+        }
 
-    pub fn desugar(&self) -> Result<DesugaredBrainFuckProgram, UnbalancedLoop> {
-        Ok(DesugaredBrainFuckProgram::from_instructions(Self::desugar_iter(self.0.iter())?))
+        let source_map = SourceMap::new(self.source_entries(origins), self.source.clone());
+        Ok(program.with_source_map(source_map))
     }
 }
 
+// the instructions a desugared instruction came from, by index
+struct Origin {
+    first: usize,
+    last: usize,
+    // the closing bracket of a loop
+    end: Option<usize>,
+    body: Vec<Origin>,
+}
+
 impl Display for BrainFuckProgram {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        for i in &self.0 {
-            write!(f, "{}", match i {
-                BrainFuckInstruction::Add => '+',
-                BrainFuckInstruction::Sub => '-',
-                BrainFuckInstruction::Left => '<',
-                BrainFuckInstruction::Right => '>',
-                BrainFuckInstruction::LoopStart => '[',
-                BrainFuckInstruction::LoopEnd => ']',
-                BrainFuckInstruction::Input => ',',
-                BrainFuckInstruction::Output => '.',
-            })?
+        for i in &self.instructions {
+            write!(f, "{}", i.to_char())?
         }
         writeln!(f)
     }
@@ -171,13 +307,21 @@ impl FromStr for BrainFuckProgram {
     type Err = Infallible;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut res = Vec::new();
+        let mut instructions = Vec::new();
+        let mut positions = Vec::new();
+        let mut position = SourcePosition::start();
         for i in s.chars() {
-            if let Some(i) = BrainFuckInstruction::from_char(i) {
-                res.push(i);
+            if let Some(instruction) = BrainFuckInstruction::from_char(i) {
+                instructions.push(instruction);
+                positions.push(position);
             }
+            position = position.advance(i);
         }
 
-        Ok(Self(res))
+        Ok(Self {
+            instructions,
+            positions,
+            source: Some(s.to_string()),
+        })
     }
 }
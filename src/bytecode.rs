@@ -0,0 +1,264 @@
+use std::fmt::{Display, Formatter};
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, ProgramCounter, SourceMap};
+
+/// A single bytecode instruction. Loops become a pair of jumps to the instruction after the
+/// other end of the loop.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Op {
+    Add(usize),
+    Sub(usize),
+    Move(isize),
+    /// sets the cell, keeping the lowest bits of the value
+    Set(u32),
+    AddAt(isize, usize),
+    SubAt(isize, usize),
+    SetAt(isize, u32),
+    /// the targets are in [`Bytecode::targets`]
+    MultiplyAdd(usize),
+    Scan(isize),
+    Input,
+    Output,
+    /// the bytes are in [`Bytecode::bytes`]
+    Write(usize),
+    JumpIfZero(usize),
+    JumpIfNotZero(usize),
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Op::Add(n) => write!(f, "add {n}"),
+            Op::Sub(n) => write!(f, "sub {n}"),
+            Op::Move(n) => write!(f, "move {n}"),
+            Op::Set(n) => write!(f, "set {n}"),
+            Op::AddAt(offset, n) => write!(f, "add {n} at {offset}"),
+            Op::SubAt(offset, n) => write!(f, "sub {n} at {offset}"),
+            Op::SetAt(offset, n) => write!(f, "set {n} at {offset}"),
+            Op::MultiplyAdd(idx) => write!(f, "multiply-add #{idx}"),
+            Op::Scan(stride) => write!(f, "scan {stride}"),
+            Op::Input => write!(f, "input"),
+            Op::Output => write!(f, "output"),
+            Op::Write(idx) => write!(f, "write #{idx}"),
+            Op::JumpIfZero(target) => write!(f, "jz {target}"),
+            Op::JumpIfNotZero(target) => write!(f, "jnz {target}"),
+        }
+    }
+}
+
+/// A desugared program flattened into a list of [`Op`]s, which
+/// [`BrainFuckInterpreter::execute_bytecode`](crate::BrainFuckInterpreter::execute_bytecode)
+/// runs without recursing into loops.
+///
+/// Every op counts as one instruction, just like in the desugared program: the jump at the start
+/// of a loop is its first check, the jump at the end every check after that.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Bytecode {
+    ops: Vec<Op>,
+    // the instruction in the desugared program every op came from
+    pcs: Vec<ProgramCounter>,
+    // the offsets and factors of every multiply-add
+    targets: Vec<Vec<(isize, isize)>>,
+    // the output of every write
+    bytes: Vec<Vec<u8>>,
+    source_map: Option<SourceMap>,
+}
+
+impl Bytecode {
+    pub fn compile(program: &DesugaredBrainFuckProgram) -> Self {
+        let mut res = Self {
+            ops: Vec::new(),
+            pcs: Vec::new(),
+            targets: Vec::new(),
+            bytes: Vec::new(),
+            source_map: program.source_map().cloned(),
+        };
+        res.compile_block(program.as_slice(), &mut Vec::new());
+        res
+    }
+
+    fn push(&mut self, op: Op, path: &[usize]) {
+        self.ops.push(op);
+        self.pcs.push(ProgramCounter::new(path.to_vec()));
+    }
+
+    fn compile_block(&mut self, block: &[DesugaredBrainFuckInstruction], path: &mut Vec<usize>) {
+        for (idx, instruction) in block.iter().enumerate() {
+            path.push(idx);
+            let op = match instruction {
+                DesugaredBrainFuckInstruction::Add(n) => Op::Add(*n),
+                DesugaredBrainFuckInstruction::Sub(n) => Op::Sub(*n),
+                DesugaredBrainFuckInstruction::Left(n) => Op::Move(-(*n as isize)),
+                DesugaredBrainFuckInstruction::Right(n) => Op::Move(*n as isize),
+                DesugaredBrainFuckInstruction::Zero => Op::Set(0),
+                DesugaredBrainFuckInstruction::Set(v) => Op::Set(*v as u32),
+                DesugaredBrainFuckInstruction::SetWide(v, _) => Op::Set(*v),
+                DesugaredBrainFuckInstruction::AddAt(offset, n) => Op::AddAt(*offset, *n),
+                DesugaredBrainFuckInstruction::SubAt(offset, n) => Op::SubAt(*offset, *n),
+                DesugaredBrainFuckInstruction::SetAt(offset, v, _) => Op::SetAt(*offset, *v),
+                DesugaredBrainFuckInstruction::MultiplyAdd(targets) => {
+                    self.targets.push(targets.clone());
+                    Op::MultiplyAdd(self.targets.len() - 1)
+                }
+                DesugaredBrainFuckInstruction::Scan { stride } => Op::Scan(*stride),
+                DesugaredBrainFuckInstruction::Input => Op::Input,
+                DesugaredBrainFuckInstruction::Output => Op::Output,
+                DesugaredBrainFuckInstruction::Write(bytes) => {
+                    self.bytes.push(bytes.clone());
+                    Op::Write(self.bytes.len() - 1)
+                }
+                DesugaredBrainFuckInstruction::Loop(body) => {
+                    let start = self.ops.len();
+                    // patched once the end of the loop is known
+                    self.push(Op::JumpIfZero(0), path);
+                    self.compile_block(body, path);
+
+                    // like the tree interpreter, checks after the body are at the end of the body
+                    path.push(body.len());
+                    self.push(Op::JumpIfNotZero(start + 1), path);
+                    path.pop();
+                    self.ops[start] = Op::JumpIfZero(self.ops.len());
+
+                    path.pop();
+                    continue;
+                }
+            };
+            self.push(op, path);
+            path.pop();
+        }
+    }
+
+    pub fn ops(&self) -> &[Op] {
+        &self.ops
+    }
+
+    /// The offsets and factors of the multiply-add with the given index.
+    pub fn targets(&self, idx: usize) -> &[(isize, isize)] {
+        &self.targets[idx]
+    }
+
+    /// The bytes the write with the given index outputs.
+    pub fn bytes(&self, idx: usize) -> &[u8] {
+        &self.bytes[idx]
+    }
+
+    /// The instruction in the desugared program the op at `index` came from.
+    pub fn pc(&self, index: usize) -> Option<&ProgramCounter> {
+        self.pcs.get(index)
+    }
+
+    /// The source map of the program this was compiled from, for its program counters.
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+}
+
+impl From<&DesugaredBrainFuckProgram> for Bytecode {
+    fn from(program: &DesugaredBrainFuckProgram) -> Self {
+        Self::compile(program)
+    }
+}
+
+impl Display for Bytecode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (idx, op) in self.ops.iter().enumerate() {
+            writeln!(f, "{idx:>6}  {op}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::bytecode::{Bytecode, Op};
+    use crate::desugared_brainfuck::{DesugaredBrainFuckProgram, ProgramCounter};
+    use crate::interpreter::{BrainFuckInterpreter, ExecutionSummary, InterpreterConfig, RuntimeError};
+    use crate::low_intermediate::LowLevelIntermediateProgram;
+    use crate::optimize::{optimize, partially_evaluate};
+    use crate::sandbox::{Limit, LimitExceeded, Limits};
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    #[test]
+    fn jumps() {
+        let bytecode = Bytecode::compile(&program("+[>[-]<-]."));
+        assert_eq!(bytecode.ops(), [
+            Op::Add(1),
+            Op::JumpIfZero(7),
+            Op::Move(1),
+            Op::Set(0),
+            Op::Move(-1),
+            Op::Sub(1),
+            Op::JumpIfNotZero(2),
+            Op::Output,
+        ]);
+        assert_eq!(bytecode.pc(6), Some(&ProgramCounter::new(vec![1, 4])));
+    }
+
+    // runs a program with both engines, and checks that they agree
+    fn run_both(program: DesugaredBrainFuckProgram, input: &[u8], config: InterpreterConfig) -> (Vec<u8>, Result<u64, String>) {
+        let summarize = |res: Result<ExecutionSummary, RuntimeError>| res.map(|i| (i.instructions, i.ptr)).map_err(|e| e.to_string());
+
+        let mut bytecode_output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(&mut bytecode_output, input, config);
+        let bytecode_res = summarize(interpreter.execute_bytecode(&Bytecode::compile(&program)));
+        let bytecode_bounds = interpreter.tape().bounds();
+        drop(interpreter);
+
+        let mut tree_output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(&mut tree_output, input, config);
+        assert_eq!(bytecode_res, summarize(interpreter.execute(program)));
+        assert_eq!(bytecode_bounds, interpreter.tape().bounds());
+        drop(interpreter);
+        assert_eq!(bytecode_output, tree_output);
+
+        (bytecode_output, bytecode_res.map(|(instructions, _)| instructions))
+    }
+
+    #[test]
+    fn same_as_tree() {
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let (output, _) = run_both(program(hello), b"", InterpreterConfig::default());
+        assert_eq!(output, b"Hello World!\n");
+        let (output, _) = run_both(optimize(&program(hello)), b"", InterpreterConfig::default());
+        assert_eq!(output, b"Hello World!\n");
+        let (output, _) = run_both(partially_evaluate::<u8>(&program(hello), InterpreterConfig::default(), 10_000), b"", InterpreterConfig::default());
+        assert_eq!(output, b"Hello World!\n");
+
+        let (output, _) = run_both(program(",[.,]"), b"echo", InterpreterConfig::default());
+        assert_eq!(output, b"echo");
+
+        let lil = LowLevelIntermediateProgram::parse("one = 1;\na = 5;\nb = 0;\nwhile a != 0 {\n    b += a;\n    a -= one;\n}").unwrap();
+        let (_, res) = run_both(lil.compile(), b"", InterpreterConfig::default());
+        assert!(res.is_ok());
+        let (_, optimized) = run_both(optimize(&lil.compile()), b"", InterpreterConfig::default());
+        assert!(optimized < res);
+
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Fixed(4), EdgePolicy::Error).unwrap(),
+            ..Default::default()
+        };
+        let (output, res) = run_both(program("+[>+.]"), b"", config);
+        assert_eq!(output, [1, 1, 1]);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn limits() {
+        let config = InterpreterConfig {
+            limits: Limits { fuel: Some(10), ..Default::default() },
+            ..Default::default()
+        };
+        let (_, res) = run_both(program("++++++++[>++++++++<-]>+."), b"", config);
+        assert!(res.unwrap_err().starts_with("ran out of fuel after 10 instructions, at instruction 1.3"));
+
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let res = interpreter.execute_bytecode(&Bytecode::compile(&program("+[]")));
+        assert!(matches!(res, Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, instructions: 10, .. }))));
+    }
+}
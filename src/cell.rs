@@ -0,0 +1,149 @@
+use std::fmt::{Debug, Display, Formatter};
+use std::hash::Hash;
+use std::str::FromStr;
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
+}
+
+impl CellWidth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    pub fn max_value(&self) -> u32 {
+        u32::MAX >> (32 - self.bits())
+    }
+}
+
+impl Display for CellWidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidCellWidth(String);
+
+impl Display for InvalidCellWidth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid cell width '{}', expected 8, 16 or 32", self.0)
+    }
+}
+
+impl std::error::Error for InvalidCellWidth {}
+
+impl FromStr for CellWidth {
+    type Err = InvalidCellWidth;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            _ => Err(InvalidCellWidth(s.to_string())),
+        }
+    }
+}
+
+/// The value stored in a single cell of the tape. All arithmetic wraps around at the width of
+/// the cell.
+pub trait Cell: Copy + Default + Eq + Hash + Debug + Display + 'static {
+    const WIDTH: CellWidth;
+
+    fn wrapping_add_usize(self, n: usize) -> Self;
+    fn wrapping_sub_usize(self, n: usize) -> Self;
+    /// keeps the lowest bits of `value`
+    fn from_u32(value: u32) -> Self;
+    fn to_u32(self) -> u32;
+
+    /// input bytes are zero extended
+    fn from_byte(byte: u8) -> Self {
+        Self::from_u32(byte as u32)
+    }
+
+    /// output only writes the lowest byte
+    fn to_byte(self) -> u8 {
+        self.to_u32() as u8
+    }
+
+    fn is_zero(self) -> bool {
+        self == Self::default()
+    }
+
+    /// The index of the first zero in `cells`.
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().position(|i| i.is_zero())
+    }
+
+    /// The index of the last zero in `cells`.
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        cells.iter().rposition(|i| i.is_zero())
+    }
+}
+
+macro_rules! impl_cell {
+    ($ty: ty, $width: expr $(, $extra: item)*) => {
+        impl Cell for $ty {
+            const WIDTH: CellWidth = $width;
+
+            fn wrapping_add_usize(self, n: usize) -> Self {
+                // usize wraps at a multiple of the cell size, so truncating afterwards is exact
+                (self as usize).wrapping_add(n) as $ty
+            }
+
+            fn wrapping_sub_usize(self, n: usize) -> Self {
+                (self as usize).wrapping_sub(n) as $ty
+            }
+
+            fn from_u32(value: u32) -> Self {
+                value as $ty
+            }
+
+            fn to_u32(self) -> u32 {
+                self as u32
+            }
+
+            $($extra)*
+        }
+    };
+}
+
+// bytes can be searched a word at a time
+impl_cell!(
+    u8,
+    CellWidth::U8,
+    fn find_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memchr(0, cells)
+    },
+    fn rfind_zero(cells: &[Self]) -> Option<usize> {
+        memchr::memrchr(0, cells)
+    }
+);
+impl_cell!(u16, CellWidth::U16);
+impl_cell!(u32, CellWidth::U32);
+
+#[cfg(test)]
+mod tests {
+    use crate::cell::{Cell, CellWidth};
+
+    #[test]
+    fn wrapping() {
+        assert_eq!(255u8.wrapping_add_usize(1), 0);
+        assert_eq!(255u16.wrapping_add_usize(1), 256);
+        assert_eq!(0u16.wrapping_sub_usize(1), u16::MAX);
+        assert_eq!(0u32.wrapping_sub_usize(2), u32::MAX - 1);
+        assert_eq!(0x1234u16.to_byte(), 0x34);
+        assert_eq!(u16::from_u32(0x12345), 0x2345);
+        assert_eq!(CellWidth::U16.max_value(), 65535);
+        assert_eq!("32".parse::<CellWidth>().unwrap(), CellWidth::U32);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use crate::desugared_brainfuck::ProgramCounter;
+use crate::profile::{InstructionProfile, Profile};
+use crate::source::Span;
+
+/// Coverage can only be merged with coverage of the same program.
+#[derive(Debug)]
+pub struct CoverageMismatch;
+
+impl Display for CoverageMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "the coverage is of a different program")
+    }
+}
+
+impl std::error::Error for CoverageMismatch {}
+
+/// A line of saved [counts](Coverage::counts) that can't be read.
+#[derive(Debug)]
+pub struct InvalidCounts {
+    pub line: usize,
+}
+
+impl Display for InvalidCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid coverage counts on line {}", self.line)
+    }
+}
+
+impl std::error::Error for InvalidCounts {}
+
+/// Which instructions of a program ran, and how often, over one or more runs. Made from the
+/// [`Profile`] of a run, and [merged](Self::merge) with the coverage of other runs of the same
+/// program, for example the runs over a test corpus.
+#[derive(Clone, Debug)]
+pub struct Coverage {
+    instructions: Vec<InstructionProfile>,
+    source: Option<String>,
+}
+
+impl From<&Profile> for Coverage {
+    fn from(profile: &Profile) -> Self {
+        Self {
+            instructions: profile.instructions().to_vec(),
+            source: profile.source().map(str::to_string),
+        }
+    }
+}
+
+impl Coverage {
+    /// Every instruction with the number of times it ran, in program order.
+    pub fn instructions(&self) -> &[InstructionProfile] {
+        &self.instructions
+    }
+
+    /// Reads counts saved with [`counts`](Self::counts). They don't include the source, but can
+    /// still be [merged](Self::merge) into the coverage of another run of the program.
+    pub fn from_counts(counts: &str) -> Result<Self, InvalidCounts> {
+        let instructions = counts.lines()
+            .enumerate()
+            .map(|(idx, line)| {
+                let mut parts = line.splitn(4, ' ');
+                let mut next = || parts.next().ok_or(InvalidCounts { line: idx + 1 });
+                let path = next()?.split('.').map(str::parse).collect::<Result<_, _>>();
+                let executed = next()?.parse();
+                let iterations = next()?.parse();
+                let name = next()?.to_string();
+                match (path, executed, iterations) {
+                    (Ok(path), Ok(executed), Ok(iterations)) => Ok(InstructionProfile {
+                        pc: ProgramCounter::new(path),
+                        name,
+                        span: None,
+                        executed,
+                        iterations,
+                    }),
+                    _ => Err(InvalidCounts { line: idx + 1 }),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Self {
+            instructions,
+            source: None,
+        })
+    }
+
+    /// Adds the counts of another run of the same program.
+    pub fn merge(&mut self, other: &Coverage) -> Result<(), CoverageMismatch> {
+        let same = self.instructions.len() == other.instructions.len()
+            && self.instructions.iter().zip(&other.instructions).all(|(a, b)| a.pc == b.pc && a.name == b.name);
+        if !same {
+            return Err(CoverageMismatch);
+        }
+
+        for (a, b) in self.instructions.iter_mut().zip(&other.instructions) {
+            a.executed += b.executed;
+            a.iterations += b.iterations;
+        }
+        Ok(())
+    }
+
+    /// The instructions that never ran.
+    pub fn unexecuted(&self) -> impl Iterator<Item=&InstructionProfile> {
+        self.instructions.iter().filter(|i| i.executed == 0)
+    }
+
+    /// The loops that were started, but never ran their body.
+    pub fn unentered_loops(&self) -> impl Iterator<Item=&InstructionProfile> {
+        self.instructions.iter().filter(|i| i.name == "loop" && i.executed > 0 && i.iterations == 0)
+    }
+
+    /// The source with how often every line ran, marking the parts that never did. Programs
+    /// without a source list their instructions instead.
+    pub fn listing(&self) -> Listing<'_> {
+        Listing(self)
+    }
+
+    /// An lcov tracefile for the source in `source_file`, with line counts and, for loops, how
+    /// often they ran their body and how often they finished.
+    pub fn lcov<'a>(&'a self, source_file: &'a str) -> Lcov<'a> {
+        Lcov {
+            coverage: self,
+            source_file,
+        }
+    }
+
+    /// The counts of every instruction, one per line, to save and read back with
+    /// [`from_counts`](Self::from_counts) to collect coverage over separate processes.
+    pub fn counts(&self) -> Counts<'_> {
+        Counts(self)
+    }
+
+    // how often the most executed instruction of every span ran
+    fn spans(&self) -> Vec<(Span, u64)> {
+        let mut counts = HashMap::new();
+        for i in &self.instructions {
+            if let Some(span) = i.span {
+                let count = counts.entry(span).or_insert(0);
+                *count = i.executed.max(*count);
+            }
+        }
+
+        // outer spans before the spans nested in them
+        let mut res: Vec<_> = counts.into_iter().collect();
+        res.sort_by_key(|(span, _)| (span.start.offset, std::cmp::Reverse(span.end.offset)));
+        res
+    }
+
+    // how often every line with instructions on it ran
+    fn lines(&self) -> BTreeMap<usize, u64> {
+        let mut res = BTreeMap::new();
+        for (span, executed) in self.spans() {
+            let count = res.entry(span.start.line).or_insert(0);
+            *count = executed.max(*count);
+        }
+        res
+    }
+}
+
+pub struct Listing<'a>(&'a Coverage);
+
+impl Display for Listing<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let coverage = self.0;
+        let fmt_count = |f: &mut Formatter<'_>, count: Option<u64>| match count {
+            Some(0) => write!(f, "{:>9}", "#####"),
+            Some(count) => write!(f, "{count:>9}"),
+            None => write!(f, "{:>9}", "-"),
+        };
+
+        let Some(source) = &coverage.source else {
+            for i in &coverage.instructions {
+                fmt_count(f, Some(i.executed))?;
+                writeln!(f, " | {} {}", i.pc, i.name)?;
+            }
+            return Ok(());
+        };
+
+        // whether the innermost instruction at every byte of the source ran
+        let mut ran = vec![None; source.len()];
+        for (span, executed) in coverage.spans() {
+            if let Some(bytes) = ran.get_mut(span.start.offset..span.end.offset) {
+                bytes.fill(Some(executed > 0));
+            }
+        }
+
+        let lines = coverage.lines();
+        let mut offset = 0;
+        let text = source.strip_suffix('\n').unwrap_or(source);
+        for (idx, line) in text.split('\n').enumerate() {
+            let text = line.strip_suffix('\r').unwrap_or(line);
+            let count = lines.get(&(idx + 1)).copied();
+            fmt_count(f, count)?;
+            writeln!(f, " | {:>5} | {text}", idx + 1)?;
+
+            // lines that didn't run at all are already marked
+            let marks: String = text.char_indices()
+                .map(|(i, c)| match ran[offset + i] {
+                    Some(false) => '^',
+                    _ if c == '\t' => '\t',
+                    _ => ' ',
+                })
+                .collect();
+            if count != Some(0) && marks.contains('^') {
+                writeln!(f, "{:>9} | {:>5} | {}", "", "", marks.trim_end())?;
+            }
+            offset += line.len() + 1;
+        }
+        Ok(())
+    }
+}
+
+pub struct Counts<'a>(&'a Coverage);
+
+impl Display for Counts<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for i in &self.0.instructions {
+            writeln!(f, "{} {} {} {}", i.pc, i.executed, i.iterations, i.name)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Lcov<'a> {
+    coverage: &'a Coverage,
+    source_file: &'a str,
+}
+
+impl Display for Lcov<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "TN:")?;
+        writeln!(f, "SF:{}", self.source_file)?;
+
+        let loops = self.coverage.instructions.iter().filter(|i| i.name == "loop");
+        let mut branches = 0;
+        let mut branches_hit = 0;
+        for (block, i) in loops.enumerate() {
+            let Some(span) = i.span else {
+                continue;
+            };
+            // the body, and leaving the loop
+            for (branch, taken) in [i.iterations, i.executed].into_iter().enumerate() {
+                if i.executed == 0 {
+                    writeln!(f, "BRDA:{},{block},{branch},-", span.start.line)?;
+                } else {
+                    writeln!(f, "BRDA:{},{block},{branch},{taken}", span.start.line)?;
+                }
+                branches += 1;
+                branches_hit += usize::from(taken > 0);
+            }
+        }
+        writeln!(f, "BRF:{branches}")?;
+        writeln!(f, "BRH:{branches_hit}")?;
+
+        let lines = self.coverage.lines();
+        for (line, count) in &lines {
+            writeln!(f, "DA:{line},{count}")?;
+        }
+        writeln!(f, "LF:{}", lines.len())?;
+        writeln!(f, "LH:{}", lines.values().filter(|i| **i > 0).count())?;
+        writeln!(f, "end_of_record")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::coverage::Coverage;
+    use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig};
+    use crate::low_intermediate::LowLevelIntermediateProgram;
+
+    fn coverage(program: DesugaredBrainFuckProgram, input: &[u8]) -> Coverage {
+        let config = InterpreterConfig { profile: true, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), input, config);
+        interpreter.execute(program).unwrap();
+        Coverage::from(interpreter.profile().unwrap())
+    }
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    #[test]
+    fn merge_runs() {
+        let code = ",[>+<-]\n>.";
+        let mut merged = coverage(program(code), b"");
+        assert_eq!(merged.unexecuted().count(), 4);
+        assert_eq!(merged.unentered_loops().count(), 1);
+        assert_eq!(merged.listing().to_string(), [
+            "        1 |     1 | ,[>+<-]",
+            "          |       |   ^^^^",
+            "        1 |     2 | >.",
+            "",
+        ].join("\n"));
+
+        merged.merge(&coverage(program(code), b"\x02")).unwrap();
+        assert_eq!(merged.unexecuted().count(), 0);
+        let lp = &merged.instructions()[1];
+        assert_eq!((lp.executed, lp.iterations), (2, 2));
+        assert!(!merged.listing().to_string().contains('^'));
+
+        assert!(merged.merge(&coverage(program("+."), b"")).is_err());
+    }
+
+    #[test]
+    fn saved_counts() {
+        let code = ",[>+<-]\n>.";
+        let first = coverage(program(code), b"");
+        let counts = first.counts().to_string();
+        assert!(counts.starts_with("0 1 0 input\n1 1 0 loop\n1.0 0 0 right 1\n"), "{counts}");
+
+        // merged into a run with the source, which is kept
+        let mut merged = coverage(program(code), b"\x02");
+        merged.merge(&Coverage::from_counts(&counts).unwrap()).unwrap();
+        let lp = &merged.instructions()[1];
+        assert_eq!((lp.executed, lp.iterations), (2, 2));
+        assert!(merged.listing().to_string().contains("        2 |     1 | ,[>+<-]"));
+
+        assert_eq!(Coverage::from_counts(&merged.counts().to_string()).unwrap().counts().to_string(), merged.counts().to_string());
+        assert_eq!(Coverage::from_counts("0 1 0 input\n1 x 0 loop\n").unwrap_err().line, 2);
+        assert_eq!(Coverage::from_counts("0 1\n").unwrap_err().line, 1);
+    }
+
+    #[test]
+    fn lil_lcov() {
+        let code = "one = 1;\na = 0;\nwhile a != 0 {\n    a -= one;\n}\nprint a;";
+        let program = LowLevelIntermediateProgram::parse(code).unwrap();
+        let coverage = coverage(program.compile(), b"");
+
+        let listing = coverage.listing().to_string();
+        assert!(listing.contains("    ##### |     4 |     a -= one;"), "{listing}");
+        assert!(listing.contains("        - |     5 | }"), "{listing}");
+
+        let lcov = coverage.lcov("count.lil").to_string();
+        assert!(lcov.starts_with("TN:\nSF:count.lil\n"), "{lcov}");
+        assert!(lcov.contains("\nDA:3,1\nDA:4,0\nDA:6,1\n"), "{lcov}");
+        assert!(lcov.contains("\nLF:5\nLH:4\nend_of_record\n"), "{lcov}");
+    }
+}
@@ -0,0 +1,160 @@
+use std::io::{stdin, stdout, Stdin, Stdout, Write};
+use brainfuck_compiler::{BrainFuckInterpreter, DesugaredBrainFuckProgram, RuntimeError};
+use brainfuck_compiler::cell::Cell;
+use brainfuck_compiler::debugger::{Debugger, Stop};
+use brainfuck_compiler::interpreter::InterpreterConfig;
+
+const HELP: &str = "\
+    step [N], s     run the next N instructions (default 1)
+    next, n         like step, but runs a loop to the end when stopped at its start
+    continue, c     run until a breakpoint, a watchpoint or the end of the program
+    break L:C, b    stop at the first instruction at or after line L, column C
+    delete L:C      remove the breakpoint at line L, column C
+    watch CELL, w   stop when the cell at position CELL changes
+    unwatch CELL    stop watching the cell at position CELL
+    tape [N], t     show N cells on either side of the data pointer (default 8)
+    where           show the next instruction
+    quit, q         exit";
+
+pub struct DebugShell<C: Cell> {
+    debugger: Debugger<Stdout, Stdin, C>,
+}
+
+impl<C: Cell> DebugShell<C> {
+    pub fn new(program: DesugaredBrainFuckProgram, config: InterpreterConfig) -> Result<Self, RuntimeError> {
+        let interpreter = BrainFuckInterpreter::with_config(stdout(), stdin(), config);
+        Ok(Self {
+            debugger: Debugger::new(interpreter, program)?,
+        })
+    }
+
+    pub fn run(&mut self) -> std::io::Result<()> {
+        println!("brainfuck debugger, type help for help");
+        self.show_location();
+
+        loop {
+            print!("(debug) ");
+            stdout().flush()?;
+
+            // without keeping stdin locked, since the program reads from it too
+            let mut line = String::new();
+            if stdin().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim();
+            let (name, arg) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            if !self.command(name, arg.trim()) {
+                return Ok(());
+            }
+        }
+    }
+
+    // returns false when the debugger should exit
+    fn command(&mut self, name: &str, arg: &str) -> bool {
+        match name {
+            "" => {}
+            "help" | "h" => println!("{HELP}"),
+            "quit" | "q" => return false,
+            "step" | "s" => {
+                let Ok(n) = (if arg.is_empty() { Ok(1) } else { arg.parse::<usize>() }) else {
+                    println!("error: expected a number of instructions, not '{arg}'");
+                    return true;
+                };
+                let mut res = Ok(Stop::Step);
+                for _ in 0..n {
+                    res = self.debugger.step();
+                    if !matches!(res, Ok(Stop::Step)) {
+                        break;
+                    }
+                }
+                self.stopped(res);
+            }
+            "next" | "n" => {
+                let res = self.debugger.step_over();
+                self.stopped(res);
+            }
+            "continue" | "c" => {
+                let res = self.debugger.run();
+                self.stopped(res);
+            }
+            "break" | "b" | "delete" => {
+                let Some((line, column)) = parse_location(arg) else {
+                    println!("error: expected a location like 3:14, not '{arg}'");
+                    return true;
+                };
+                if name == "delete" {
+                    match self.debugger.source_map().and_then(|i| i.find(line, column)) {
+                        Some(pc) if self.debugger.remove_breakpoint(&pc) => println!("deleted breakpoint at {line}:{column}"),
+                        _ => println!("error: no breakpoint at {line}:{column}"),
+                    }
+                } else {
+                    match self.debugger.add_breakpoint(line, column) {
+                        Some(pc) => println!("breakpoint at instruction {pc}"),
+                        None => println!("error: no instruction at or after {line}:{column}"),
+                    }
+                }
+            }
+            "watch" | "w" | "unwatch" => {
+                let Ok(position) = arg.parse() else {
+                    println!("error: expected a cell position, not '{arg}'");
+                    return true;
+                };
+                if name == "unwatch" {
+                    if !self.debugger.unwatch(position) {
+                        println!("error: cell {position} isn't watched");
+                    }
+                } else {
+                    self.debugger.watch(position);
+                }
+            }
+            "tape" | "t" => match if arg.is_empty() { Ok(8) } else { arg.parse() } {
+                Ok(radius) => println!("{}", self.debugger.interpreter().tape().window(radius)),
+                Err(_) => println!("error: expected a number of cells, not '{arg}'"),
+            },
+            "where" => self.show_location(),
+            _ => println!("error: unknown command '{name}', type help for help"),
+        }
+
+        true
+    }
+
+    fn stopped(&self, res: Result<Stop<C>, RuntimeError>) {
+        // the program's output doesn't have to end with a newline
+        println!();
+        match res {
+            Ok(Stop::Step) => {}
+            Ok(Stop::Breakpoint(pc)) => println!("breakpoint at instruction {pc}"),
+            Ok(Stop::Watchpoint { position, old, new }) => {
+                let show = |i: Option<C>| i.map_or("-".to_string(), |i| i.to_string());
+                println!("cell {position} changed from {} to {}", show(old), show(new));
+            }
+            Ok(Stop::Finished(summary)) => {
+                println!("program finished after {} instructions", summary.instructions);
+            }
+            Err(e) => println!("error: {e}"),
+        }
+
+        if self.debugger.pc().is_some() {
+            self.show_location();
+            println!("{}", self.debugger.interpreter().tape().window(8));
+        }
+    }
+
+    fn show_location(&self) {
+        let Some(pc) = self.debugger.pc() else {
+            println!("the program has finished");
+            return;
+        };
+
+        match (self.debugger.position(), self.debugger.snippet()) {
+            (Some(position), Some(snippet)) => println!("at {position}\n{snippet}"),
+            (Some(position), None) => println!("at {position}"),
+            _ => println!("at instruction {pc}"),
+        }
+    }
+}
+
+fn parse_location(s: &str) -> Option<(usize, usize)> {
+    let (line, column) = s.split_once(':')?;
+    Some((line.parse().ok()?, column.parse().ok()?))
+}
@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use crate::cell::Cell;
+use crate::desugared_brainfuck::{DesugaredBrainFuckProgram, ProgramCounter, SourceMap};
+use crate::interpreter::{BrainFuckInterpreter, ExecutionSummary, RuntimeError};
+use crate::sandbox::{Limit, LimitExceeded};
+use crate::source::{Snippet, SourcePosition, Span};
+
+/// Why the debugger stopped.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Stop<C> {
+    /// the requested steps ran
+    Step,
+    /// the next instruction has a breakpoint
+    Breakpoint(ProgramCounter),
+    /// a watched cell changed
+    Watchpoint {
+        position: isize,
+        old: Option<C>,
+        new: Option<C>,
+    },
+    /// the program ran to the end
+    Finished(ExecutionSummary),
+}
+
+/// Runs a program an instruction at a time. The debugger uses the interpreter's fuel to stop
+/// after every instruction, and stops with [`Limit::Fuel`] once the program ran as many
+/// instructions as the interpreter's own fuel limit allows.
+pub struct Debugger<W: Write, R, C: Cell = u8> {
+    interpreter: BrainFuckInterpreter<W, R, C>,
+    source_map: Option<SourceMap>,
+    breakpoints: HashSet<ProgramCounter>,
+    // the last value seen in every watched cell
+    watchpoints: HashMap<isize, Option<C>>,
+    finished: Option<ExecutionSummary>,
+    // what is left of the interpreter's own fuel
+    fuel: Option<u64>,
+    executed: u64,
+}
+
+impl<W: Write, R: Read, C: Cell> Debugger<W, R, C> {
+    /// Loads `program` into the interpreter, stopped before its first instruction.
+    pub fn new(mut interpreter: BrainFuckInterpreter<W, R, C>, program: DesugaredBrainFuckProgram) -> Result<Self, RuntimeError> {
+        let source_map = program.source_map().cloned();
+        let fuel = interpreter.limits().fuel;
+        interpreter.limits_mut().fuel = Some(0);
+
+        let finished = match interpreter.execute(program) {
+            Ok(summary) => Some(summary),
+            Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, .. })) => None,
+            Err(e) => return Err(e),
+        };
+
+        Ok(Self {
+            interpreter,
+            source_map,
+            breakpoints: HashSet::new(),
+            watchpoints: HashMap::new(),
+            finished,
+            fuel,
+            executed: 0,
+        })
+    }
+
+    pub fn interpreter(&self) -> &BrainFuckInterpreter<W, R, C> {
+        &self.interpreter
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// The instruction that runs next, or `None` once the program finished.
+    pub fn pc(&self) -> Option<&ProgramCounter> {
+        self.interpreter.paused_at()
+    }
+
+    /// Where the next instruction is in the source, if the program has a source map.
+    pub fn span(&self) -> Option<Span> {
+        self.source_map.as_ref()?.span(self.pc()?)
+    }
+
+    pub fn position(&self) -> Option<SourcePosition> {
+        self.span().map(|i| i.start)
+    }
+
+    /// The line of source with the next instruction marked, if the source was kept.
+    pub fn snippet(&self) -> Option<Snippet> {
+        let span = self.span()?;
+        let source = self.source_map.as_ref()?.source()?;
+        Some(Snippet::new(source, span.start, span.len()))
+    }
+
+    /// Sets a breakpoint on the first instruction at or after a line and column, and returns
+    /// that instruction. Returns `None` if there is no such instruction or no source map.
+    pub fn add_breakpoint(&mut self, line: usize, column: usize) -> Option<ProgramCounter> {
+        let pc = self.source_map.as_ref()?.find(line, column)?;
+        self.breakpoints.insert(pc.clone());
+        Some(pc)
+    }
+
+    pub fn add_breakpoint_at(&mut self, pc: ProgramCounter) {
+        self.breakpoints.insert(pc);
+    }
+
+    /// Returns whether there was a breakpoint.
+    pub fn remove_breakpoint(&mut self, pc: &ProgramCounter) -> bool {
+        self.breakpoints.remove(pc)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item=&ProgramCounter> {
+        self.breakpoints.iter()
+    }
+
+    /// Stops whenever the cell at `position` changes.
+    pub fn watch(&mut self, position: isize) {
+        let value = self.interpreter.tape().get_at(position);
+        self.watchpoints.insert(position, value);
+    }
+
+    /// Returns whether the cell was watched.
+    pub fn unwatch(&mut self, position: isize) -> bool {
+        self.watchpoints.remove(&position).is_some()
+    }
+
+    // runs a single instruction, without looking at breakpoints
+    fn step_once(&mut self) -> Result<Stop<C>, RuntimeError> {
+        if let Some(summary) = self.finished {
+            return Ok(Stop::Finished(summary));
+        }
+
+        if self.fuel == Some(0) {
+            return Err(RuntimeError::Limit(LimitExceeded {
+                limit: Limit::Fuel,
+                pc: self.pc().cloned().unwrap_or_default(),
+                ptr: self.interpreter.ptr(),
+                instructions: self.executed,
+            }));
+        }
+
+        self.interpreter.add_fuel(1);
+        let res = self.interpreter.resume();
+        if matches!(res, Ok(_) | Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, .. }))) {
+            self.executed += 1;
+            if let Some(fuel) = &mut self.fuel {
+                *fuel -= 1;
+            }
+        }
+        match res {
+            Ok(summary) => {
+                // the last instruction can still change a watched cell
+                self.finished = Some(summary);
+                Ok(match self.check_watchpoints() {
+                    Stop::Step => Stop::Finished(summary),
+                    stop => stop,
+                })
+            }
+            Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, .. })) => Ok(self.check_watchpoints()),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Stop<C> {
+        let tape = self.interpreter.tape();
+        let mut changed = self.watchpoints.iter_mut()
+            .filter_map(|(position, old)| {
+                let new = tape.get_at(*position);
+                (new != *old).then(|| (*position, std::mem::replace(old, new), new))
+            })
+            .collect::<Vec<_>>();
+        changed.sort_by_key(|(position, ..)| *position);
+
+        match changed.first() {
+            Some(&(position, old, new)) => Stop::Watchpoint { position, old, new },
+            None => Stop::Step,
+        }
+    }
+
+    // keeps stepping while `keep_going` holds for the next instruction, stopping early at
+    // breakpoints, watchpoints and the end of the program
+    fn step_while(&mut self, mut keep_going: impl FnMut(&ProgramCounter) -> bool) -> Result<Stop<C>, RuntimeError> {
+        loop {
+            let stop = self.step_once()?;
+            if stop != Stop::Step {
+                return Ok(stop);
+            }
+
+            let Some(pc) = self.pc() else {
+                return Ok(stop);
+            };
+            if self.breakpoints.contains(pc) {
+                return Ok(Stop::Breakpoint(pc.clone()));
+            }
+            if !keep_going(pc) {
+                return Ok(Stop::Step);
+            }
+        }
+    }
+
+    /// Runs the next instruction. A loop counts as one instruction every time it checks its
+    /// condition.
+    pub fn step(&mut self) -> Result<Stop<C>, RuntimeError> {
+        self.step_while(|_| false)
+    }
+
+    /// Like [`step`](Self::step), but runs a loop to the end when stopped at its start.
+    pub fn step_over(&mut self) -> Result<Stop<C>, RuntimeError> {
+        let Some(start) = self.pc().cloned() else {
+            return self.step();
+        };
+        self.step_while(|pc| pc.is_inside(&start))
+    }
+
+    /// Runs until a breakpoint, a watchpoint or the end of the program.
+    pub fn run(&mut self) -> Result<Stop<C>, RuntimeError> {
+        self.step_while(|_| true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::debugger::{Debugger, Stop};
+    use crate::desugared_brainfuck::ProgramCounter;
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig, RuntimeError};
+    use crate::sandbox::{Limit, LimitExceeded, Limits};
+
+    fn debugger(code: &str) -> Debugger<Vec<u8>, std::io::Empty> {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        Debugger::new(BrainFuckInterpreter::new(Vec::new(), empty()), program.desugar().unwrap()).unwrap()
+    }
+
+    fn at(line: usize, column: usize) -> (usize, usize) {
+        (line, column)
+    }
+
+    fn position(debugger: &Debugger<Vec<u8>, std::io::Empty>) -> Option<(usize, usize)> {
+        debugger.position().map(|i| (i.line, i.column))
+    }
+
+    #[test]
+    fn stepping() {
+        let mut debugger = debugger("++\n[->+<]\n>.");
+        assert_eq!(position(&debugger), Some(at(1, 1)));
+        assert_eq!(debugger.step().unwrap(), Stop::Step);
+        assert_eq!(position(&debugger), Some(at(2, 1)));
+
+        // into the loop, and the check at its end
+        debugger.step().unwrap();
+        assert_eq!(debugger.pc(), Some(&ProgramCounter::new(vec![1, 0])));
+        for _ in 0..4 {
+            debugger.step().unwrap();
+        }
+        assert_eq!(position(&debugger), Some(at(2, 6)));
+        assert_eq!(debugger.snippet().unwrap().to_string(), "  |\n2 | [->+<]\n  |      ^");
+
+        // back at the start of the body, stepping over the loop doesn't apply
+        debugger.step().unwrap();
+        assert_eq!(debugger.pc(), Some(&ProgramCounter::new(vec![1, 0])));
+
+        let stop = debugger.run().unwrap();
+        assert!(matches!(stop, Stop::Finished(summary) if summary.ptr == 1));
+        assert_eq!(debugger.pc(), None);
+        assert_eq!(debugger.interpreter().tape().get_at(1), Some(2));
+    }
+
+    #[test]
+    fn step_over_loops() {
+        let mut debugger = debugger("+++[>++<-]>.");
+        debugger.step().unwrap();
+        assert_eq!(debugger.step_over().unwrap(), Stop::Step);
+        assert_eq!(debugger.pc(), Some(&ProgramCounter::new(vec![2])));
+        assert_eq!(debugger.interpreter().tape().get_at(1), Some(6));
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut debugger = debugger("+++[>+<-]\n>.");
+        let pc = debugger.add_breakpoint(1, 6).unwrap();
+        assert_eq!(pc, ProgramCounter::new(vec![1, 1]));
+
+        for i in 0..3 {
+            assert_eq!(debugger.run().unwrap(), Stop::Breakpoint(pc.clone()));
+            assert_eq!(debugger.interpreter().tape().get_at(1), Some(i));
+        }
+        assert!(debugger.remove_breakpoint(&pc));
+
+        // between the lines, the next instruction is on the second one
+        let pc = debugger.add_breakpoint(1, 10).unwrap();
+        assert_eq!(pc, ProgramCounter::new(vec![2]));
+        assert_eq!(debugger.run().unwrap(), Stop::Breakpoint(pc));
+        assert!(matches!(debugger.run().unwrap(), Stop::Finished(_)));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut debugger = debugger("++[>+++<-]>[-]");
+        debugger.watch(1);
+        assert_eq!(debugger.run().unwrap(), Stop::Watchpoint { position: 1, old: Some(0), new: Some(3) });
+        assert_eq!(debugger.run().unwrap(), Stop::Watchpoint { position: 1, old: Some(3), new: Some(6) });
+        assert_eq!(debugger.run().unwrap(), Stop::Watchpoint { position: 1, old: Some(6), new: Some(0) });
+        assert!(matches!(debugger.run().unwrap(), Stop::Finished(_)));
+    }
+
+    #[test]
+    fn keeps_fuel_limit() {
+        let program: BrainFuckProgram = "+++[->+<]".parse().unwrap();
+        let config = InterpreterConfig { limits: Limits { fuel: Some(4), ..Default::default() }, ..Default::default() };
+        let interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let mut debugger = Debugger::new(interpreter, program.desugar().unwrap()).unwrap();
+        for _ in 0..4 {
+            assert_eq!(debugger.step().unwrap(), Stop::Step);
+        }
+        let res = debugger.run();
+        assert!(matches!(res, Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, instructions: 4, .. }))), "{res:?}");
+        assert_eq!(debugger.pc(), Some(&ProgramCounter::new(vec![1, 2])));
+    }
+}
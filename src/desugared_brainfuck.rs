@@ -1,8 +1,9 @@
 use std::fmt::{Display, Formatter};
-use std::iter;
 use crate::brainfuck::{BrainFuckInstruction, BrainFuckProgram};
+use crate::cell::CellWidth;
+use crate::source::{SourcePosition, Span};
 
-#[derive(Clone, PartialEq)]
+#[derive(Clone, PartialEq, Debug)]
 pub enum DesugaredBrainFuckInstruction {
     Add(usize),
     Sub(usize),
@@ -11,17 +12,60 @@ pub enum DesugaredBrainFuckInstruction {
     Loop(Vec<DesugaredBrainFuckInstruction>),
     Zero,
     Set(u8),
+    /// sets the cell to a value meant for cells of the given width. On narrower cells, the value
+    /// is truncated just like the resugared code would.
+    SetWide(u32, CellWidth),
+    /// adds to the cell at an offset from the data pointer, without moving it
+    AddAt(isize, usize),
+    /// subtracts from the cell at an offset from the data pointer, without moving it
+    SubAt(isize, usize),
+    /// sets the cell at an offset from the data pointer like [`SetWide`](Self::SetWide), without
+    /// moving it
+    SetAt(isize, u32, CellWidth),
+    /// a loop that subtracts one from its cell and adds constants to cells at fixed offsets. It
+    /// adds `factor` times the cell to the cell at every offset, then clears the cell.
+    MultiplyAdd(Vec<(isize, isize)>),
+    /// a loop that only moves the pointer, like `[>]` or `[<<]`: moves the pointer `stride`
+    /// cells at a time until it finds a zero
+    Scan { stride: isize },
     Input,
     Output,
+    /// sets the cell to every byte in turn and outputs it, then clears the cell
+    Write(Vec<u8>),
 }
 
 impl DesugaredBrainFuckInstruction {
+    /// A short description, like `add 3`. Loops are just `loop`, without their body.
+    pub fn name(&self) -> String {
+        match self {
+            DesugaredBrainFuckInstruction::Add(n) => format!("add {n}"),
+            DesugaredBrainFuckInstruction::Sub(n) => format!("sub {n}"),
+            DesugaredBrainFuckInstruction::Left(n) => format!("left {n}"),
+            DesugaredBrainFuckInstruction::Right(n) => format!("right {n}"),
+            DesugaredBrainFuckInstruction::Loop(_) => "loop".to_string(),
+            DesugaredBrainFuckInstruction::Zero => "zero".to_string(),
+            DesugaredBrainFuckInstruction::Set(n) => format!("set {n}"),
+            DesugaredBrainFuckInstruction::SetWide(n, width) => format!("set {n} ({width} bit)"),
+            DesugaredBrainFuckInstruction::AddAt(offset, n) => format!("add {n} at {offset}"),
+            DesugaredBrainFuckInstruction::SubAt(offset, n) => format!("sub {n} at {offset}"),
+            DesugaredBrainFuckInstruction::SetAt(offset, n, width) => format!("set {n} ({width} bit) at {offset}"),
+            DesugaredBrainFuckInstruction::MultiplyAdd(targets) => {
+                let targets: Vec<_> = targets.iter().map(|(offset, factor)| format!("{factor} at {offset}")).collect();
+                format!("multiply-add {}", targets.join(", "))
+            }
+            DesugaredBrainFuckInstruction::Scan { stride } => format!("scan {stride}"),
+            DesugaredBrainFuckInstruction::Input => "input".to_string(),
+            DesugaredBrainFuckInstruction::Output => "output".to_string(),
+            DesugaredBrainFuckInstruction::Write(bytes) => format!("write \"{}\"", bytes.escape_ascii()),
+        }
+    }
+
     pub fn resugar(&self) -> Vec<BrainFuckInstruction> {
         match self {
-            DesugaredBrainFuckInstruction::Add(n) => iter::repeat(BrainFuckInstruction::Add).take(*n).collect(),
-            DesugaredBrainFuckInstruction::Sub(n) => iter::repeat(BrainFuckInstruction::Sub).take(*n).collect(),
-            DesugaredBrainFuckInstruction::Left(n) => iter::repeat(BrainFuckInstruction::Left).take(*n).collect(),
-            DesugaredBrainFuckInstruction::Right(n) => iter::repeat(BrainFuckInstruction::Right).take(*n).collect(),
+            DesugaredBrainFuckInstruction::Add(n) => vec![BrainFuckInstruction::Add; *n],
+            DesugaredBrainFuckInstruction::Sub(n) => vec![BrainFuckInstruction::Sub; *n],
+            DesugaredBrainFuckInstruction::Left(n) => vec![BrainFuckInstruction::Left; *n],
+            DesugaredBrainFuckInstruction::Right(n) => vec![BrainFuckInstruction::Right; *n],
             DesugaredBrainFuckInstruction::Loop(v) => {
                 let mut res = Vec::new();
                 res.push(BrainFuckInstruction::LoopStart);
@@ -31,31 +75,250 @@ impl DesugaredBrainFuckInstruction {
             }
             DesugaredBrainFuckInstruction::Zero => vec![BrainFuckInstruction::LoopStart, BrainFuckInstruction::Sub, BrainFuckInstruction::LoopEnd],
             DesugaredBrainFuckInstruction::Set(n) => BrainFuckInstruction::set(*n),
+            DesugaredBrainFuckInstruction::SetWide(n, width) => BrainFuckInstruction::set_for(*n, *width),
+            DesugaredBrainFuckInstruction::AddAt(offset, n) => Self::resugar_at(*offset, vec![BrainFuckInstruction::Add; *n]),
+            DesugaredBrainFuckInstruction::SubAt(offset, n) => Self::resugar_at(*offset, vec![BrainFuckInstruction::Sub; *n]),
+            DesugaredBrainFuckInstruction::SetAt(offset, n, width) => Self::resugar_at(*offset, BrainFuckInstruction::set_for(*n, *width)),
+            DesugaredBrainFuckInstruction::MultiplyAdd(targets) => {
+                let mut res = vec![BrainFuckInstruction::LoopStart, BrainFuckInstruction::Sub];
+                for (offset, factor) in targets {
+                    let change = if *factor < 0 { BrainFuckInstruction::Sub } else { BrainFuckInstruction::Add };
+                    res.extend(Self::resugar_at(*offset, vec![change; factor.unsigned_abs()]));
+                }
+                res.push(BrainFuckInstruction::LoopEnd);
+                res
+            }
+            DesugaredBrainFuckInstruction::Scan { stride } => {
+                let direction = if *stride < 0 { BrainFuckInstruction::Left } else { BrainFuckInstruction::Right };
+                let mut res = vec![BrainFuckInstruction::LoopStart];
+                res.extend(vec![direction; stride.unsigned_abs()]);
+                res.push(BrainFuckInstruction::LoopEnd);
+                res
+            }
             DesugaredBrainFuckInstruction::Input => vec![BrainFuckInstruction::Input],
             DesugaredBrainFuckInstruction::Output => vec![BrainFuckInstruction::Output],
+            DesugaredBrainFuckInstruction::Write(bytes) => {
+                let mut res = Vec::new();
+                for byte in bytes {
+                    res.extend(BrainFuckInstruction::set(*byte));
+                    res.push(BrainFuckInstruction::Output);
+                }
+                res.extend([BrainFuckInstruction::LoopStart, BrainFuckInstruction::Sub, BrainFuckInstruction::LoopEnd]);
+                res
+            }
+        }
+    }
+
+    // moves to the cell at `offset` for `instructions`, and back
+    fn resugar_at(offset: isize, instructions: Vec<BrainFuckInstruction>) -> Vec<BrainFuckInstruction> {
+        let (there, back) = if offset < 0 {
+            (BrainFuckInstruction::Left, BrainFuckInstruction::Right)
+        } else {
+            (BrainFuckInstruction::Right, BrainFuckInstruction::Left)
+        };
+        let mut res = vec![there; offset.unsigned_abs()];
+        res.extend(instructions);
+        res.extend(vec![back; offset.unsigned_abs()]);
+        res
+    }
+}
+
+/// The position of an instruction in a desugared program: its index in the program and, for
+/// instructions in loops, the index in every loop body going inwards. An index one past the end
+/// of a loop body is the point where the loop checks its condition again.
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
+pub struct ProgramCounter(Vec<usize>);
+
+impl ProgramCounter {
+    pub fn new(path: Vec<usize>) -> Self {
+        Self(path)
+    }
+
+    pub fn path(&self) -> &[usize] {
+        &self.0
+    }
+
+    /// Whether `self` is an instruction inside the loop at `other`.
+    pub fn is_inside(&self, other: &ProgramCounter) -> bool {
+        self.0.len() > other.0.len() && self.0.starts_with(&other.0)
+    }
+}
+
+impl Display for ProgramCounter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (idx, i) in self.0.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ".")?;
+            }
+            write!(f, "{i}")?;
         }
+        Ok(())
     }
 }
 
-pub struct DesugaredBrainFuckProgram(Vec<DesugaredBrainFuckInstruction>);
+/// Where in the source a desugared instruction came from.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SourceEntry {
+    pub span: Span,
+    /// for loops, the closing bracket
+    pub end: Option<Span>,
+    /// for loops, the entries of the body
+    pub body: Vec<SourceEntry>,
+}
+
+/// Where every instruction of a desugared program came from, in the same shape as the program.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SourceMap {
+    entries: Vec<SourceEntry>,
+    source: Option<String>,
+}
+
+impl SourceMap {
+    pub fn new(entries: Vec<SourceEntry>, source: Option<String>) -> Self {
+        Self {
+            entries,
+            source,
+        }
+    }
+
+    pub fn entries(&self) -> &[SourceEntry] {
+        &self.entries
+    }
+
+    /// The source code the spans point into, if it was kept.
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn span(&self, pc: &ProgramCounter) -> Option<Span> {
+        let (last, outer) = pc.path().split_last()?;
+        let mut block = &self.entries;
+        let mut parent = None;
+        for idx in outer {
+            let entry = block.get(*idx)?;
+            block = &entry.body;
+            parent = Some(entry);
+        }
+
+        match block.get(*last) {
+            Some(entry) => Some(entry.span),
+            None if *last == block.len() => parent?.end,
+            None => None,
+        }
+    }
+
+    /// The first instruction at or after a line and column, including the points where loops
+    /// check their condition again.
+    pub fn find(&self, line: usize, column: usize) -> Option<ProgramCounter> {
+        fn after(end: SourcePosition, position: (usize, usize)) -> bool {
+            (end.line, end.column) > position
+        }
+
+        fn find_in(block: &[SourceEntry], position: (usize, usize), path: &mut Vec<usize>) -> bool {
+            for (idx, entry) in block.iter().enumerate() {
+                path.push(idx);
+                if after(entry.span.end, position) {
+                    return true;
+                }
+                if find_in(&entry.body, position, path) {
+                    return true;
+                }
+                if matches!(entry.end, Some(end) if after(end.end, position)) {
+                    path.push(entry.body.len());
+                    return true;
+                }
+                path.pop();
+            }
+            false
+        }
+
+        let mut path = Vec::new();
+        find_in(&self.entries, (line, column), &mut path).then_some(ProgramCounter(path))
+    }
+}
+
+#[derive(Clone)]
+pub struct DesugaredBrainFuckProgram {
+    instructions: Vec<DesugaredBrainFuckInstruction>,
+    source_map: Option<SourceMap>,
+}
 
 impl DesugaredBrainFuckProgram {
     pub fn from_instructions(v: impl AsRef<[DesugaredBrainFuckInstruction]>) -> Self {
-        Self(v.as_ref().to_vec())
+        Self {
+            instructions: v.as_ref().to_vec(),
+            source_map: None,
+        }
+    }
+
+    pub fn with_source_map(self, source_map: SourceMap) -> Self {
+        Self {
+            source_map: Some(source_map),
+            ..self
+        }
     }
 
     pub fn as_slice(&self) -> &[DesugaredBrainFuckInstruction] {
-        &self.0
+        &self.instructions
+    }
+
+    pub fn source_map(&self) -> Option<&SourceMap> {
+        self.source_map.as_ref()
+    }
+
+    /// The instruction at `pc`, or `None` if `pc` is past the end of a block.
+    pub fn get(&self, pc: &ProgramCounter) -> Option<&DesugaredBrainFuckInstruction> {
+        let (last, outer) = pc.path().split_last()?;
+        let mut block = &self.instructions;
+        for idx in outer {
+            match block.get(*idx)? {
+                DesugaredBrainFuckInstruction::Loop(body) => block = body,
+                _ => return None,
+            }
+        }
+        block.get(*last)
     }
 
     pub fn resugar(&self) -> BrainFuckProgram {
         let mut res = Vec::new();
-        for i in &self.0 {
+        for i in &self.instructions {
             res.extend(i.resugar());
         }
 
         BrainFuckProgram::from_instructions(res)
     }
+
+    pub fn structured(&self) -> Structured<'_> {
+        Structured(&self.instructions)
+    }
+}
+
+/// Displays a desugared program with one instruction per line, keeping the run lengths and loop
+/// nesting visible instead of resugaring it.
+pub struct Structured<'a>(&'a [DesugaredBrainFuckInstruction]);
+
+impl Structured<'_> {
+    fn fmt_block(f: &mut Formatter<'_>, block: &[DesugaredBrainFuckInstruction], depth: usize) -> std::fmt::Result {
+        for i in block {
+            write!(f, "{:level$}", "", level = depth * 4)?;
+            match i {
+                DesugaredBrainFuckInstruction::Loop(body) => {
+                    writeln!(f, "loop {{")?;
+                    Self::fmt_block(f, body, depth + 1)?;
+                    write!(f, "{:level$}", "", level = depth * 4)?;
+                    writeln!(f, "}}")?;
+                }
+                i => writeln!(f, "{}", i.name())?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Structured<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Self::fmt_block(f, self.0, 0)
+    }
 }
 
 impl Display for DesugaredBrainFuckProgram {
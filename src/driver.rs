@@ -0,0 +1,654 @@
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::{stdin, stdout, BufReader, BufWriter, Read};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use brainfuck_compiler::{BrainFuckInterpreter, BrainFuckProgram, Bytecode, Coverage, DesugaredBrainFuckProgram, ParseError, RuntimeError, Snapshot, SnapshotError, UnbalancedLoops};
+use brainfuck_compiler::cell::{Cell, CellWidth};
+use brainfuck_compiler::emit::{Artifacts, Stage};
+use brainfuck_compiler::interpreter::InterpreterConfig;
+use brainfuck_compiler::tape::TapeConfig;
+use crate::debug::DebugShell;
+use crate::repl::{Mode, Repl};
+
+const USAGE: &str = "\
+usage:
+    brainfuck-compiler run [--bf | --lil] [--emit STAGES] [--cell-width BITS] [-O] [--evaluate FUEL] [INTERPRETER OPTIONS] [FILE]
+    brainfuck-compiler compile [--emit STAGES] [--cell-width BITS] [-O] [--evaluate FUEL] [FILE] [-o OUT]
+    brainfuck-compiler check [--bf | --lil] [--emit STAGES] [--cell-width BITS] [-O] [--evaluate FUEL] [FILE]
+    brainfuck-compiler repl [--bf | --lil] [--cell-width BITS] [INTERPRETER OPTIONS]
+    brainfuck-compiler debug [--bf | --lil] [--cell-width BITS] [-O] [--evaluate FUEL] [INTERPRETER OPTIONS] FILE
+
+FILE defaults to `-`, which reads the program from stdin. `run` and `check` treat
+files ending in `.lil` as LIL and everything else as brainfuck, `compile` always
+expects LIL. `debug` steps through a program, type `help` at its prompt for the
+commands it takes.
+
+--emit takes a comma separated list of `STAGE` or `STAGE=FILE` and writes those
+intermediate forms of the program to stdout or to FILE. It can be given more than
+once. The stages are:
+    lil         the parsed LIL program
+    desugared   the desugared program, one instruction per line
+    bf          the final brainfuck code
+
+--cell-width sets the size of a cell to 8 (default), 16 or 32 bits. Cells wrap around
+at that size, output writes the lowest byte of a cell, and LIL programs may use
+constants up to the largest value a cell can hold.
+
+-O or --optimize optimizes the desugared program, before emitting, compiling or running
+it. The optimized program writes the same output, but runs fewer instructions. Like
+--evaluate, it optimizes for the tape given to `run` or `debug`. With --strict, it
+leaves out the rewrites that would change where the program stops.
+
+--evaluate runs the program up to the first time it reads input, for at most FUEL
+instructions, while compiling it. What ran is replaced by a single write of its
+output and the tape it left behind. It evaluates on the tape given to `run` or
+`debug`, and on the default tape otherwise.
+
+interpreter options:
+    --tape KIND         fixed:N (default fixed:30000), grow-right[:N], infinite or sparse:N
+    --edges POLICY      what happens when the pointer moves off the tape: wrap (default),
+                        error or grow. Ignored for edges that always grow.
+    --eof POLICY        what `,` stores once the input has run out: zero (default),
+                        minus-one, unchanged, or error to stop the program
+    --input MODE        raw (default) passes input bytes through unchanged, line reads
+                        a line at a time and ends the last line with a newline
+    --engine ENGINE     bytecode (default) or tree, which walks the program's loops
+                        directly. Both write exactly the same output. (only for `run`)
+    --strict            stop the program instead of letting the pointer move off the tape
+                        or a cell wrap around, and show where it happened
+    --profile           after running, print how often the hottest loops and lines of
+                        the program ran to stderr. Uses the tree engine. (only for `run`)
+    --coverage FORMATS  after running, write which parts of the program ran, as a comma
+                        separated list of `FORMAT` or `FORMAT=FILE`. `listing` is the
+                        source with line counts and markers under parts that never ran,
+                        `lcov` an lcov tracefile. Without a file they go to stderr.
+                        `counts=FILE` keeps the raw counts in FILE and adds those of
+                        every run to it, so the other formats cover all runs so far.
+                        Uses the tree engine. (only for `run`)
+    --save-snapshot F   when the program stops early, for example because it ran out of
+                        fuel, save its state to F. Uses the tree engine. (only for `run`)
+    --restore F         continue the program from the snapshot in F instead of starting
+                        it from the beginning. Uses the tree engine. (only for `run`)
+
+sandbox options, for programs that can't be trusted to stop:
+    --fuel N            stop after running N instructions
+    --max-output BYTES  stop before writing more than BYTES bytes
+    --max-tape CELLS    stop before the tape grows past CELLS cells
+    --timeout SECONDS   stop after about SECONDS seconds
+
+exit codes:
+    0   success
+    1   could not read or write a file
+    2   invalid command line
+    3   unbalanced loop in a brainfuck program
+    4   syntax error in a LIL program
+    5   input or output failed while running the program, or the input ran out
+        with --eof error
+    6   the program failed at runtime, for example by moving off the tape or by
+        wrapping around with --strict
+    7   the program reached one of the sandbox limits";
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Language {
+    BrainFuck,
+    Lil,
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum Engine {
+    Tree,
+    Bytecode,
+}
+
+// files to continue a program from and to save it to when it stops early
+#[derive(Default)]
+struct Snapshots {
+    restore: Option<PathBuf>,
+    save: Option<PathBuf>,
+}
+
+enum Source {
+    Stdin,
+    File(PathBuf),
+}
+
+impl Source {
+    fn new(arg: Option<String>) -> Self {
+        match arg {
+            None => Source::Stdin,
+            Some(i) if i == "-" => Source::Stdin,
+            Some(i) => Source::File(i.into()),
+        }
+    }
+
+    fn language(&self) -> Language {
+        match self {
+            Source::File(path) if path.extension() == Some(OsStr::new("lil")) => Language::Lil,
+            _ => Language::BrainFuck,
+        }
+    }
+
+    // how the source is called in reports
+    fn name(&self) -> String {
+        match self {
+            Source::Stdin => "-".to_string(),
+            Source::File(path) => path.display().to_string(),
+        }
+    }
+
+    fn read(&self) -> Result<String, DriverError> {
+        match self {
+            Source::Stdin => {
+                let mut res = String::new();
+                stdin().read_to_string(&mut res)
+                    .map_err(|e| DriverError::Io(format!("couldn't read program from stdin: {e}")))?;
+                Ok(res)
+            }
+            Source::File(path) => fs::read_to_string(path)
+                .map_err(|e| DriverError::Io(format!("couldn't read {}: {e}", path.display()))),
+        }
+    }
+}
+
+struct Emit {
+    stage: Stage,
+    path: Option<PathBuf>,
+}
+
+impl Emit {
+    fn parse(spec: &str) -> Result<Vec<Self>, DriverError> {
+        spec.split(',')
+            .map(|i| {
+                let (stage, path) = match i.split_once('=') {
+                    Some((stage, path)) => (stage, Some(PathBuf::from(path))),
+                    None => (i, None),
+                };
+                let stage = stage.parse().map_err(|e| DriverError::Usage(format!("{e}")))?;
+                Ok(Self { stage, path })
+            })
+            .collect()
+    }
+
+    fn write(&self, artifacts: &Artifacts) -> Result<(), DriverError> {
+        let Some(code) = artifacts.emit(self.stage) else {
+            return Err(DriverError::Usage(format!("stage '{}' is only available for LIL programs", self.stage)));
+        };
+
+        match &self.path {
+            Some(path) => fs::write(path, code)
+                .map_err(|e| DriverError::Io(format!("couldn't write {}: {e}", path.display()))),
+            None => {
+                print!("{code}");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+enum CoverageFormat {
+    Listing,
+    Lcov,
+    Counts,
+}
+
+struct CoverageReport {
+    format: CoverageFormat,
+    path: Option<PathBuf>,
+}
+
+impl CoverageReport {
+    fn parse(spec: &str) -> Result<Vec<Self>, DriverError> {
+        spec.split(',')
+            .map(|i| {
+                let (format, path) = match i.split_once('=') {
+                    Some((format, path)) => (format, Some(PathBuf::from(path))),
+                    None => (i, None),
+                };
+                let format = match format {
+                    "listing" => CoverageFormat::Listing,
+                    "lcov" => CoverageFormat::Lcov,
+                    "counts" if path.is_none() => return Err(DriverError::Usage("coverage counts need a file, as counts=FILE".to_string())),
+                    "counts" => CoverageFormat::Counts,
+                    _ => return Err(DriverError::Usage(format!("unknown coverage format '{format}', expected listing, lcov or counts"))),
+                };
+                Ok(Self { format, path })
+            })
+            .collect()
+    }
+
+    // adds the counts already in the file to the coverage of this run
+    fn accumulate(&self, coverage: &mut Coverage) -> Result<(), DriverError> {
+        let (CoverageFormat::Counts, Some(path)) = (self.format, &self.path) else {
+            return Ok(());
+        };
+        let counts = match fs::read_to_string(path) {
+            Ok(counts) => counts,
+            // the first run starts the file
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(DriverError::Io(format!("couldn't read {}: {e}", path.display()))),
+        };
+        Coverage::from_counts(&counts)
+            .map_err(|e| e.to_string())
+            .and_then(|saved| coverage.merge(&saved).map_err(|e| e.to_string()))
+            .map_err(|e| DriverError::Io(format!("couldn't add to {}: {e}", path.display())))
+    }
+
+    fn write(&self, coverage: &Coverage, source: &Source) -> Result<(), DriverError> {
+        let report = match self.format {
+            CoverageFormat::Listing => coverage.listing().to_string(),
+            CoverageFormat::Lcov => coverage.lcov(&source.name()).to_string(),
+            CoverageFormat::Counts => coverage.counts().to_string(),
+        };
+
+        match &self.path {
+            Some(path) => fs::write(path, report)
+                .map_err(|e| DriverError::Io(format!("couldn't write {}: {e}", path.display()))),
+            None => {
+                eprint!("{report}");
+                Ok(())
+            }
+        }
+    }
+}
+
+enum Command {
+    Run {
+        source: Source,
+        language: Language,
+        emit: Vec<Emit>,
+        width: CellWidth,
+        optimize: bool,
+        evaluate: Option<u64>,
+        engine: Engine,
+        snapshots: Snapshots,
+        coverage: Vec<CoverageReport>,
+        config: InterpreterConfig,
+        timeout: Option<Duration>,
+    },
+    Compile {
+        source: Source,
+        output: Option<PathBuf>,
+        emit: Vec<Emit>,
+        width: CellWidth,
+        optimize: bool,
+        evaluate: Option<u64>,
+    },
+    Check {
+        source: Source,
+        language: Language,
+        emit: Vec<Emit>,
+        width: CellWidth,
+        optimize: bool,
+        evaluate: Option<u64>,
+    },
+    Debug {
+        source: Source,
+        language: Language,
+        width: CellWidth,
+        optimize: bool,
+        evaluate: Option<u64>,
+        config: InterpreterConfig,
+        timeout: Option<Duration>,
+    },
+    Repl {
+        mode: Mode,
+        width: CellWidth,
+        config: InterpreterConfig,
+        timeout: Option<Duration>,
+    },
+    Help,
+}
+
+enum DriverError {
+    Io(String),
+    Usage(String),
+    UnbalancedLoop(UnbalancedLoops),
+    Parse(ParseError),
+    Runtime(RuntimeError),
+}
+
+impl DriverError {
+    fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            DriverError::Io(_) => 1,
+            DriverError::Usage(_) => 2,
+            DriverError::UnbalancedLoop(_) => 3,
+            DriverError::Parse(_) => 4,
+            DriverError::Runtime(RuntimeError::Input(_) | RuntimeError::Output(_) | RuntimeError::EndOfInput) => 5,
+            DriverError::Runtime(RuntimeError::Limit(_)) => 7,
+            DriverError::Runtime(_) => 6,
+        })
+    }
+}
+
+impl Display for DriverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DriverError::Io(e) => write!(f, "{e}"),
+            DriverError::Usage(e) => write!(f, "{e}\n\n{USAGE}"),
+            DriverError::UnbalancedLoop(e) => write!(f, "{e}"),
+            DriverError::Parse(e) => write!(f, "syntax error: {e}"),
+            DriverError::Runtime(e) => write!(f, "runtime error: {e}"),
+        }
+    }
+}
+
+fn parse_args(args: impl Iterator<Item=String>) -> Result<Command, DriverError> {
+    let mut args = args.peekable();
+    let Some(command) = args.next() else {
+        return Err(DriverError::Usage("expected a command".to_string()));
+    };
+
+    let mut file = None;
+    let mut language = None;
+    let mut output = None;
+    let mut emit = Vec::new();
+    let mut width = CellWidth::default();
+    let mut optimize = false;
+    let mut evaluate = None;
+    let mut config = InterpreterConfig::default();
+    let (mut tape_kind, mut edges) = (config.tape.kind(), config.tape.edges());
+    let mut timeout = None;
+    let mut interpreter_options = false;
+    let mut engine = None;
+    let mut snapshots = Snapshots::default();
+    let mut coverage = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bf" => language = Some(Language::BrainFuck),
+            "--lil" => language = Some(Language::Lil),
+            "-o" | "--output" => {
+                let Some(path) = args.next() else {
+                    return Err(DriverError::Usage(format!("expected a file name after '{arg}'")));
+                };
+                output = Some(PathBuf::from(path));
+            }
+            "--emit" => {
+                let Some(spec) = args.next() else {
+                    return Err(DriverError::Usage("expected a list of stages after '--emit'".to_string()));
+                };
+                emit.extend(Emit::parse(&spec)?);
+            }
+            "-O" | "--optimize" => optimize = true,
+            "--evaluate" => {
+                let Some(value) = args.next() else {
+                    return Err(DriverError::Usage("expected a number after '--evaluate'".to_string()));
+                };
+                evaluate = Some(value.parse().map_err(|_| DriverError::Usage(format!("invalid value '{value}' for '--evaluate'")))?);
+            }
+            "--cell-width" => {
+                let Some(value) = args.next() else {
+                    return Err(DriverError::Usage("expected a number of bits after '--cell-width'".to_string()));
+                };
+                width = value.parse().map_err(|e| DriverError::Usage(format!("{e}")))?;
+            }
+            "--fuel" | "--max-output" | "--max-tape" | "--timeout" => {
+                let Some(value) = args.next() else {
+                    return Err(DriverError::Usage(format!("expected a number after '{arg}'")));
+                };
+                let invalid = || DriverError::Usage(format!("invalid value '{value}' for '{arg}'"));
+                let limits = &mut config.limits;
+                match arg.as_str() {
+                    "--fuel" => limits.fuel = Some(value.parse().map_err(|_| invalid())?),
+                    "--max-output" => limits.max_output = Some(value.parse().map_err(|_| invalid())?),
+                    "--max-tape" => limits.max_tape = Some(value.parse().map_err(|_| invalid())?),
+                    _ => {
+                        timeout = Some(Duration::try_from_secs_f64(value.parse().map_err(|_| invalid())?).map_err(|_| invalid())?);
+                    }
+                }
+                interpreter_options = true;
+            }
+            "--tape" | "--edges" | "--eof" | "--input" => {
+                let Some(value) = args.next() else {
+                    return Err(DriverError::Usage(format!("expected a value after '{arg}'")));
+                };
+                let res = match arg.as_str() {
+                    "--tape" => value.parse().map(|i| tape_kind = i).map_err(|e| e.to_string()),
+                    "--edges" => value.parse().map(|i| edges = i).map_err(|e| e.to_string()),
+                    "--eof" => value.parse().map(|i| config.eof = i).map_err(|e| e.to_string()),
+                    _ => value.parse().map(|i| config.input = i).map_err(|e| e.to_string()),
+                };
+                res.map_err(DriverError::Usage)?;
+                interpreter_options = true;
+            }
+            "--engine" => {
+                engine = match args.next().as_deref() {
+                    Some("tree") => Some(Engine::Tree),
+                    Some("bytecode") => Some(Engine::Bytecode),
+                    Some(other) => return Err(DriverError::Usage(format!("unknown engine '{other}', expected tree or bytecode"))),
+                    None => return Err(DriverError::Usage("expected an engine after '--engine'".to_string())),
+                };
+            }
+            "--save-snapshot" | "--restore" => {
+                let Some(path) = args.next() else {
+                    return Err(DriverError::Usage(format!("expected a file name after '{arg}'")));
+                };
+                if arg == "--restore" {
+                    snapshots.restore = Some(PathBuf::from(path));
+                } else {
+                    snapshots.save = Some(PathBuf::from(path));
+                }
+            }
+            "--coverage" => {
+                let Some(spec) = args.next() else {
+                    return Err(DriverError::Usage("expected a list of formats after '--coverage'".to_string()));
+                };
+                coverage.extend(CoverageReport::parse(&spec)?);
+                if coverage.iter().filter(|i| i.format == CoverageFormat::Counts).count() > 1 {
+                    return Err(DriverError::Usage("coverage can only be counted in one file".to_string()));
+                }
+            }
+            "--strict" => {
+                config.strict = true;
+                interpreter_options = true;
+            }
+            "--profile" => {
+                config.profile = true;
+                interpreter_options = true;
+            }
+            "-h" | "--help" => return Ok(Command::Help),
+            _ if arg.starts_with('-') && arg != "-" => {
+                return Err(DriverError::Usage(format!("unknown option '{arg}'")));
+            }
+            _ => {
+                if file.is_some() {
+                    return Err(DriverError::Usage(format!("unexpected argument '{arg}'")));
+                }
+                file = Some(arg);
+            }
+        }
+    }
+
+    config.tape = TapeConfig::new(tape_kind, edges).map_err(|e| DriverError::Usage(e.to_string()))?;
+    let source = Source::new(file);
+    match command.as_str() {
+        "run" | "check" if output.is_some() => Err(DriverError::Usage(format!("'{command}' doesn't take an output file"))),
+        "check" | "compile" if interpreter_options => Err(DriverError::Usage(format!("'{command}' doesn't run the program"))),
+        _ if engine.is_some() && command != "run" => Err(DriverError::Usage("only 'run' takes an engine".to_string())),
+        _ if (snapshots.save.is_some() || snapshots.restore.is_some()) && command != "run" => {
+            Err(DriverError::Usage("only 'run' takes snapshots".to_string()))
+        }
+        _ if !coverage.is_empty() && command != "run" => Err(DriverError::Usage("only 'run' reports coverage".to_string())),
+        "run" if (config.profile || !coverage.is_empty()) && engine == Some(Engine::Bytecode) => {
+            Err(DriverError::Usage("the bytecode engine can't profile the program".to_string()))
+        }
+        "run" if (snapshots.save.is_some() || snapshots.restore.is_some()) && engine == Some(Engine::Bytecode) => {
+            Err(DriverError::Usage("the bytecode engine can't take snapshots".to_string()))
+        }
+        "debug" | "repl" if config.profile => Err(DriverError::Usage(format!("'{command}' can't profile the program"))),
+        "run" => {
+            let tree = config.profile || !coverage.is_empty() || snapshots.save.is_some() || snapshots.restore.is_some();
+            let engine = engine.unwrap_or(if tree { Engine::Tree } else { Engine::Bytecode });
+            Ok(Command::Run { language: language.unwrap_or_else(|| source.language()), source, emit, width, optimize, evaluate, engine, snapshots, coverage, config, timeout })
+        }
+        "check" => Ok(Command::Check { language: language.unwrap_or_else(|| source.language()), source, emit, width, optimize, evaluate }),
+        "compile" if language == Some(Language::BrainFuck) => Err(DriverError::Usage("'compile' expects a LIL program".to_string())),
+        "compile" => Ok(Command::Compile { source, output, emit, width, optimize, evaluate }),
+        "debug" if output.is_some() || !emit.is_empty() => Err(DriverError::Usage("'debug' only takes a program and interpreter options".to_string())),
+        // stdin is where the debugger commands come from
+        "debug" if matches!(source, Source::Stdin) => Err(DriverError::Usage("'debug' needs a program file".to_string())),
+        "debug" => Ok(Command::Debug { language: language.unwrap_or_else(|| source.language()), source, width, optimize, evaluate, config, timeout }),
+        "repl" if output.is_some() || !emit.is_empty() || optimize || evaluate.is_some() || matches!(source, Source::File(_)) => {
+            Err(DriverError::Usage("'repl' only takes '--bf' or '--lil'".to_string()))
+        }
+        "repl" => Ok(Command::Repl {
+            mode: if language == Some(Language::Lil) { Mode::Lil } else { Mode::BrainFuck },
+            width,
+            config,
+            timeout,
+        }),
+        "help" | "-h" | "--help" => Ok(Command::Help),
+        _ => Err(DriverError::Usage(format!("unknown command '{command}'"))),
+    }
+}
+
+// optimizes and evaluates for the interpreter configuration the program runs with
+fn load(source: &Source, language: Language, emit: &[Emit], width: CellWidth, optimize: bool, evaluate: Option<u64>, config: InterpreterConfig) -> Result<Artifacts, DriverError> {
+    let code = source.read()?;
+    let mut artifacts = match language {
+        Language::BrainFuck => {
+            let program: BrainFuckProgram = code.parse().unwrap_or_else(|e| match e {});
+            Artifacts::from_brainfuck(&program).map_err(DriverError::UnbalancedLoop)?
+        }
+        Language::Lil => Artifacts::parse_lil_for(&code, width).map_err(DriverError::Parse)?,
+    };
+    if optimize {
+        artifacts.optimize_for(config);
+    }
+    if let Some(fuel) = evaluate {
+        artifacts.evaluate(width, config, fuel);
+    }
+
+    for i in emit {
+        i.write(&artifacts)?;
+    }
+
+    Ok(artifacts)
+}
+
+struct Reports<'a> {
+    source: &'a Source,
+    coverage: &'a [CoverageReport],
+}
+
+fn execute<C: Cell>(program: DesugaredBrainFuckProgram, engine: Engine, snapshots: &Snapshots, reports: Reports, mut config: InterpreterConfig) -> Result<(), DriverError> {
+    // coverage comes from the profile, which is only reported when asked for
+    let report_profile = config.profile;
+    config.profile |= !reports.coverage.is_empty();
+    let mut interpreter = BrainFuckInterpreter::<_, _, C>::with_config(stdout(), stdin(), config);
+    let res = match (engine, &snapshots.restore) {
+        (Engine::Tree, Some(path)) => {
+            fs::File::open(path)
+                .map_err(SnapshotError::Io)
+                .and_then(|file| Snapshot::read_from(BufReader::new(file)))
+                .and_then(|snapshot| interpreter.restore(snapshot, program))
+                .map_err(|e| DriverError::Io(format!("couldn't restore {}: {e}", path.display())))?;
+            interpreter.resume()
+        }
+        (Engine::Tree, None) => interpreter.execute(program),
+        (Engine::Bytecode, _) => interpreter.execute_bytecode(&Bytecode::compile(&program)),
+    };
+    // a profile of a program that failed is still useful
+    if let Some(profile) = interpreter.profile() {
+        if report_profile {
+            eprintln!("{}", profile.report(10));
+        }
+        let mut coverage = Coverage::from(profile);
+        for i in reports.coverage {
+            i.accumulate(&mut coverage)?;
+        }
+        for i in reports.coverage {
+            i.write(&coverage, reports.source)?;
+        }
+    }
+
+    if let (Some(path), Some(pc)) = (&snapshots.save, interpreter.paused_at()) {
+        let saved = fs::File::create(path).and_then(|file| interpreter.snapshot().write_to(BufWriter::new(file)));
+        match saved {
+            Ok(()) => eprintln!("saved the program at instruction {pc} to {}", path.display()),
+            Err(e) => return Err(DriverError::Io(format!("couldn't write {}: {e}", path.display()))),
+        }
+    }
+    res.map_err(DriverError::Runtime)?;
+    Ok(())
+}
+
+// the timeout only starts once the program runs, not while it's parsed and compiled
+fn start_clock(mut config: InterpreterConfig, timeout: Option<Duration>) -> InterpreterConfig {
+    config.limits.deadline = timeout.and_then(|i| Instant::now().checked_add(i));
+    config
+}
+
+fn debug<C: Cell>(program: DesugaredBrainFuckProgram, config: InterpreterConfig) -> Result<(), DriverError> {
+    DebugShell::<C>::new(program, config)
+        .map_err(DriverError::Runtime)?
+        .run()
+        .map_err(|e| DriverError::Io(format!("couldn't read from the terminal: {e}")))
+}
+
+fn run_command(command: Command) -> Result<(), DriverError> {
+    match command {
+        Command::Run { source, language, emit, width, optimize, evaluate, engine, snapshots, coverage, config, timeout } => {
+            let program = load(&source, language, &emit, width, optimize, evaluate, config)?.into_desugared();
+            let config = start_clock(config, timeout);
+            let reports = Reports { source: &source, coverage: &coverage };
+            match width {
+                CellWidth::U8 => execute::<u8>(program, engine, &snapshots, reports, config),
+                CellWidth::U16 => execute::<u16>(program, engine, &snapshots, reports, config),
+                CellWidth::U32 => execute::<u32>(program, engine, &snapshots, reports, config),
+            }
+        }
+        Command::Compile { source, output, emit, width, optimize, evaluate } => {
+            let artifacts = load(&source, Language::Lil, &emit, width, optimize, evaluate, InterpreterConfig::default())?;
+            let code = artifacts.desugared().resugar().to_string();
+            match output {
+                Some(path) => fs::write(&path, code)
+                    .map_err(|e| DriverError::Io(format!("couldn't write {}: {e}", path.display()))),
+                None => {
+                    print!("{code}");
+                    Ok(())
+                }
+            }
+        }
+        Command::Check { source, language, emit, width, optimize, evaluate } => {
+            load(&source, language, &emit, width, optimize, evaluate, InterpreterConfig::default())?;
+            Ok(())
+        }
+        Command::Debug { source, language, width, optimize, evaluate, config, timeout } => {
+            let program = load(&source, language, &[], width, optimize, evaluate, config)?.into_desugared();
+            let config = start_clock(config, timeout);
+            match width {
+                CellWidth::U8 => debug::<u8>(program, config),
+                CellWidth::U16 => debug::<u16>(program, config),
+                CellWidth::U32 => debug::<u32>(program, config),
+            }
+        }
+        Command::Repl { mode, width, config, timeout } => {
+            let config = start_clock(config, timeout);
+            match width {
+                CellWidth::U8 => Repl::<u8>::new(mode, config).run(),
+                CellWidth::U16 => Repl::<u16>::new(mode, config).run(),
+                CellWidth::U32 => Repl::<u32>::new(mode, config).run(),
+            }.map_err(|e| DriverError::Io(format!("couldn't read from the terminal: {e}")))
+        }
+        Command::Help => {
+            println!("{USAGE}");
+            Ok(())
+        }
+    }
+}
+
+pub fn main(args: impl Iterator<Item=String>) -> ExitCode {
+    match parse_args(args).and_then(run_command) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            e.exit_code()
+        }
+    }
+}
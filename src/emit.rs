@@ -0,0 +1,168 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::brainfuck::{BrainFuckProgram, UnbalancedLoops};
+use crate::cell::CellWidth;
+use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+use crate::interpreter::InterpreterConfig;
+use crate::low_intermediate::LowLevelIntermediateProgram;
+use crate::optimize::{optimize, optimize_for, partially_evaluate};
+use crate::parser::ParseError;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Stage {
+    /// the parsed LIL program, only available when compiling from LIL
+    Lil,
+    /// the desugared program, one run-length encoded instruction per line
+    Desugared,
+    /// the final brainfuck code
+    BrainFuck,
+}
+
+impl Stage {
+    pub const ALL: [Stage; 3] = [Stage::Lil, Stage::Desugared, Stage::BrainFuck];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::Lil => "lil",
+            Stage::Desugared => "desugared",
+            Stage::BrainFuck => "bf",
+        }
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownStage(String);
+
+impl Display for UnknownStage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown stage '{}', expected one of ", self.0)?;
+        for (idx, i) in Stage::ALL.iter().enumerate() {
+            if idx != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{i}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnknownStage {}
+
+impl FromStr for Stage {
+    type Err = UnknownStage;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Stage::ALL.into_iter()
+            .find(|i| i.name() == s)
+            .ok_or_else(|| UnknownStage(s.to_string()))
+    }
+}
+
+/// Everything produced while compiling a program, so that any stage can be emitted afterwards.
+pub struct Artifacts {
+    lil: Option<LowLevelIntermediateProgram>,
+    desugared: DesugaredBrainFuckProgram,
+}
+
+impl Artifacts {
+    pub fn from_brainfuck(program: &BrainFuckProgram) -> Result<Self, UnbalancedLoops> {
+        Ok(Self {
+            lil: None,
+            desugared: program.desugar()?,
+        })
+    }
+
+    pub fn from_lil(program: LowLevelIntermediateProgram) -> Self {
+        Self {
+            desugared: program.compile(),
+            lil: Some(program),
+        }
+    }
+
+    pub fn parse_lil(s: &str) -> Result<Self, ParseError> {
+        Self::parse_lil_for(s, CellWidth::U8)
+    }
+
+    pub fn parse_lil_for(s: &str, width: CellWidth) -> Result<Self, ParseError> {
+        Ok(Self::from_lil(LowLevelIntermediateProgram::parse_for(s, width)?))
+    }
+
+    /// Replaces the desugared program with an [optimized](crate::optimize::optimize) one.
+    pub fn optimize(&mut self) {
+        self.desugared = optimize(&self.desugared);
+    }
+
+    /// Replaces the desugared program with one [optimized](crate::optimize::optimize_for) for
+    /// running with `config`.
+    pub fn optimize_for(&mut self, config: InterpreterConfig) {
+        self.desugared = optimize_for(&self.desugared, config);
+    }
+
+    /// Replaces the start of the desugared program with what it
+    /// [evaluates to](crate::optimize::partially_evaluate), for cells of the given width.
+    pub fn evaluate(&mut self, width: CellWidth, config: InterpreterConfig, fuel: u64) {
+        self.desugared = match width {
+            CellWidth::U8 => partially_evaluate::<u8>(&self.desugared, config, fuel),
+            CellWidth::U16 => partially_evaluate::<u16>(&self.desugared, config, fuel),
+            CellWidth::U32 => partially_evaluate::<u32>(&self.desugared, config, fuel),
+        };
+    }
+
+    pub fn desugared(&self) -> &DesugaredBrainFuckProgram {
+        &self.desugared
+    }
+
+    pub fn into_desugared(self) -> DesugaredBrainFuckProgram {
+        self.desugared
+    }
+
+    /// Renders the given stage, or returns `None` if this compilation never went through it.
+    pub fn emit(&self, stage: Stage) -> Option<String> {
+        match stage {
+            Stage::Lil => self.lil.as_ref().map(|i| i.to_string()),
+            Stage::Desugared => Some(self.desugared.structured().to_string()),
+            Stage::BrainFuck => Some(self.desugared.resugar().to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::emit::{Artifacts, Stage};
+
+    #[test]
+    fn emit_desugared() {
+        let program: BrainFuckProgram = "+++>>[-]<[->+<].".parse().unwrap();
+        let Ok(artifacts) = Artifacts::from_brainfuck(&program) else {
+            panic!("unbalanced loop")
+        };
+
+        assert_eq!(artifacts.emit(Stage::Lil), None);
+        assert_eq!(
+            artifacts.emit(Stage::Desugared).unwrap(),
+            "add 3\nright 2\nzero\nleft 1\nloop {\n    sub 1\n    right 1\n    add 1\n    left 1\n}\noutput\n",
+        );
+        assert_eq!(artifacts.emit(Stage::BrainFuck).unwrap(), "+++>>[-]<[->+<].\n");
+    }
+
+    #[test]
+    fn emit_lil() {
+        let artifacts = Artifacts::parse_lil("a = 3; print a;").unwrap();
+        assert_eq!(artifacts.emit(Stage::Lil).unwrap(), "\nv0 = 3;\nprint v0;\n");
+    }
+
+    #[test]
+    fn parse_stage() {
+        for i in Stage::ALL {
+            assert_eq!(i.name().parse::<Stage>().unwrap(), i);
+        }
+        assert!("asm".parse::<Stage>().is_err());
+    }
+}
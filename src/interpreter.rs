@@ -1,78 +1,1066 @@
-use std::io::{BufRead, BufReader, BufWriter, Read, stdout, Write};
-use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::str::FromStr;
+use std::time::Instant;
+use crate::bytecode::{Bytecode, Op};
+use crate::cell::Cell;
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, ProgramCounter, SourceMap};
+use crate::profile::{Counter, Profile};
+use crate::sandbox::{Limit, LimitExceeded, Limits};
+use crate::snapshot::{fingerprint, points_into, Snapshot, SnapshotError};
+use crate::strict::{Trap, TrapKind};
+use crate::tape::{Tape, TapeConfig, TapeError};
 
-const MEMORY_SIZE: usize = 30_000;
+/// What `,` does once the input has run out.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum EofPolicy {
+    /// store 0
+    #[default]
+    Zero,
+    /// store -1, the largest value a cell can hold
+    MinusOne,
+    /// leave the cell as it was
+    Unchanged,
+    /// stop with [`RuntimeError::EndOfInput`]
+    Error,
+}
+
+#[derive(Debug)]
+pub struct InvalidEofPolicy(String);
+
+impl Display for InvalidEofPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid eof policy '{}', expected zero, minus-one, unchanged or error", self.0)
+    }
+}
+
+impl std::error::Error for InvalidEofPolicy {}
+
+impl FromStr for EofPolicy {
+    type Err = InvalidEofPolicy;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "zero" | "0" => Ok(EofPolicy::Zero),
+            "minus-one" | "-1" => Ok(EofPolicy::MinusOne),
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            "error" => Ok(EofPolicy::Error),
+            _ => Err(InvalidEofPolicy(s.to_string())),
+        }
+    }
+}
+
+/// How `,` reads from the input.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum InputMode {
+    /// one byte at a time, exactly as they come from the input
+    #[default]
+    Raw,
+    /// a line at a time, for typing into the program interactively. The last line gets a newline
+    /// if the input ends without one.
+    Line,
+}
+
+#[derive(Debug)]
+pub struct InvalidInputMode(String);
+
+impl Display for InvalidInputMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid input mode '{}', expected raw or line", self.0)
+    }
+}
+
+impl std::error::Error for InvalidInputMode {}
 
-pub struct BrainFuckInterpreter<W: Write, R> {
-    memory: [u8; MEMORY_SIZE],
-    ptr: usize,
+impl FromStr for InputMode {
+    type Err = InvalidInputMode;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "raw" => Ok(InputMode::Raw),
+            "line" => Ok(InputMode::Line),
+            _ => Err(InvalidInputMode(s.to_string())),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct InterpreterConfig {
+    pub tape: TapeConfig,
+    pub eof: EofPolicy,
+    pub input: InputMode,
+    pub limits: Limits,
+    /// count how often every instruction runs, see [`BrainFuckInterpreter::profile`]
+    pub profile: bool,
+    /// stop with a [`Trap`] instead of wrapping the data pointer or a cell around, or moving the
+    /// pointer left of the start of the tape
+    pub strict: bool,
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum RuntimeError {
+    /// reading from the input failed
+    Input(std::io::Error),
+    /// writing or flushing the output failed
+    Output(std::io::Error),
+    /// the data pointer left the tape
+    Tape(TapeError),
+    /// `,` was executed after the input ran out, with [`EofPolicy::Error`]
+    EndOfInput,
+    /// the program reached one of the sandbox limits
+    Limit(LimitExceeded),
+    /// the program would have wrapped around in strict mode
+    Trap(Box<Trap>),
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeError::Input(e) => write!(f, "couldn't read input: {e}"),
+            RuntimeError::Output(e) => write!(f, "couldn't write output: {e}"),
+            RuntimeError::Tape(e) => write!(f, "{e}"),
+            RuntimeError::EndOfInput => write!(f, "tried to read past the end of the input"),
+            RuntimeError::Limit(e) => write!(f, "{e}"),
+            RuntimeError::Trap(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RuntimeError::Input(e) | RuntimeError::Output(e) => Some(e),
+            RuntimeError::Tape(e) => Some(e),
+            RuntimeError::EndOfInput => None,
+            RuntimeError::Limit(e) => Some(e),
+            RuntimeError::Trap(e) => Some(e),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ExecutionSummary {
+    /// every desugared instruction counts once, loops count once for every time their condition
+    /// is checked
+    pub instructions: u64,
+    /// where the data pointer ended up
+    pub ptr: isize,
+}
+
+pub struct BrainFuckInterpreter<W: Write, R, C: Cell = u8> {
+    tape: Tape<C>,
     output: BufWriter<W>,
     input: BufReader<R>,
-    read_buf: std::vec::IntoIter<u8>,
+    read_buf: VecDeque<u8>,
+    input_mode: InputMode,
+    eof: EofPolicy,
+    limits: Limits,
+    executed: u64,
+    written: u64,
+    // a program that stopped with an error, and where to continue it
+    paused: Option<(DesugaredBrainFuckProgram, ProgramCounter)>,
+    profiling: bool,
+    // the counts of the running program, kept while it's paused
+    counters: Option<Vec<Counter>>,
+    profile: Option<Profile>,
+    strict: bool,
+    // stop for input and output instead of blocking, for machines
+    yielding: bool,
+    input_closed: bool,
+    // whether the last instruction wrote output, which a yielding interpreter stops for
+    output_pending: bool,
+}
+
+// why a yielding interpreter stopped
+pub(crate) enum Yield {
+    Input,
+    Output,
+}
+
+pub(crate) enum Progress {
+    Finished(ExecutionSummary),
+    Yielded(Yield),
+}
+
+// stops a running program. The path to the instruction is collected innermost first while
+// unwinding.
+struct Interrupt {
+    reason: Stop,
+    path: Vec<usize>,
+}
+
+enum Stop {
+    Limit(Limit),
+    Error(RuntimeError),
+    Yield(Yield),
+    Trap(TrapKind),
+}
+
+impl Interrupt {
+    fn at(mut self, idx: usize) -> Self {
+        self.path.push(idx);
+        self
+    }
+}
+
+impl From<Yield> for Interrupt {
+    fn from(reason: Yield) -> Self {
+        Self { reason: Stop::Yield(reason), path: Vec::new() }
+    }
+}
+
+impl From<TrapKind> for Interrupt {
+    fn from(kind: TrapKind) -> Self {
+        Self { reason: Stop::Trap(kind), path: Vec::new() }
+    }
+}
+
+impl From<Limit> for Interrupt {
+    fn from(limit: Limit) -> Self {
+        Self { reason: Stop::Limit(limit), path: Vec::new() }
+    }
+}
+
+impl From<RuntimeError> for Interrupt {
+    fn from(e: RuntimeError) -> Self {
+        Self { reason: Stop::Error(e), path: Vec::new() }
+    }
 }
 
 impl<W: Write, R: Read> BrainFuckInterpreter<W, R> {
+    /// An interpreter with the default configuration and 8 bit cells.
     pub fn new(output: W, input: R) -> Self {
+        Self::with_config(output, input, InterpreterConfig::default())
+    }
+}
+
+impl<W: Write, R: Read, C: Cell> BrainFuckInterpreter<W, R, C> {
+    pub fn with_config(output: W, input: R, config: InterpreterConfig) -> Self {
         Self {
-            memory: [0; MEMORY_SIZE],
-            ptr: 0,
+            tape: Tape::new(config.tape),
             output: BufWriter::new(output),
             input: BufReader::new(input),
-            read_buf: vec![].into_iter(),
+            read_buf: VecDeque::new(),
+            input_mode: config.input,
+            eof: config.eof,
+            limits: config.limits,
+            executed: 0,
+            written: 0,
+            paused: None,
+            profiling: config.profile,
+            counters: None,
+            profile: None,
+            strict: config.strict,
+            yielding: false,
+            input_closed: false,
+            output_pending: false,
         }
     }
 
-    fn execute_internal<'a>(&mut self, program: impl Iterator<Item=&'a DesugaredBrainFuckInstruction>) {
-        for i in program {
-            match i {
-                DesugaredBrainFuckInstruction::Add(i) => self.memory[self.ptr] = (self.memory[self.ptr] as usize).wrapping_add(*i) as u8,
-                DesugaredBrainFuckInstruction::Sub(i) => self.memory[self.ptr] = (self.memory[self.ptr] as usize).wrapping_sub(*i) as u8,
-                DesugaredBrainFuckInstruction::Left(l) => self.ptr = (self.ptr as isize - *l as isize).rem_euclid(MEMORY_SIZE as isize) as usize,
-                DesugaredBrainFuckInstruction::Right(r) => self.ptr = (self.ptr as isize + *r as isize).rem_euclid(MEMORY_SIZE as isize) as usize,
-                DesugaredBrainFuckInstruction::Loop(l) => {
-                    loop {
-                        if self.memory[self.ptr] == 0 {
-                            break;
-                        }
-                        self.execute_internal(l.iter());
-                    }
+    pub fn ptr(&self) -> isize {
+        self.tape.ptr()
+    }
+
+    pub fn tape(&self) -> &Tape<C> {
+        &self.tape
+    }
+
+    /// Clears the tape and moves the data pointer back to the start, and forgets a program that
+    /// stopped with an error. Buffered input is kept.
+    pub fn reset(&mut self) {
+        self.tape = Tape::new(self.tape.config());
+        self.paused = None;
+    }
+
+    /// Returns `None` at the end of the input.
+    fn read_byte(&mut self) -> Result<Option<u8>, RuntimeError> {
+        if let Some(i) = self.read_buf.pop_front() {
+            return Ok(Some(i));
+        }
+
+        // the read might block, so whatever the program printed (like a prompt) has to be visible
+        if self.input.buffer().is_empty() {
+            self.output.flush().map_err(RuntimeError::Output)?;
+        }
+
+        match self.input_mode {
+            InputMode::Raw => {
+                let byte = self.input.fill_buf().map_err(RuntimeError::Input)?.first().copied();
+                if byte.is_some() {
+                    self.input.consume(1);
                 }
-                DesugaredBrainFuckInstruction::Zero => {
-                    self.memory[self.ptr] = 0;
-                }
-                DesugaredBrainFuckInstruction::Set(v) => {
-                    self.memory[self.ptr] = *v;
-                }
-                DesugaredBrainFuckInstruction::Input => {
-                    if let Some(i) = self.read_buf.next() {
-                        self.memory[self.ptr] = i;
-                    } else {
-                        let mut buf = String::new();
-                        match self.input.read_line(&mut buf) {
-                            Ok(0) => {
-                                self.read_buf = vec![0].into_iter();
-                            }
-                            Ok(_) => {
-                                let mut res = Vec::new();
-                                res.extend(buf.bytes());
-                                res.push(10);
-                                self.read_buf = res.into_iter();
-                            }
-                            Err(e) => panic!("{e}")
-                        }
+                Ok(byte)
+            }
+            InputMode::Line => {
+                let mut line = Vec::new();
+                if self.input.read_until(b'\n', &mut line).map_err(RuntimeError::Input)? == 0 {
+                    return Ok(None);
+                }
+                if line.last() != Some(&b'\n') {
+                    line.push(b'\n');
+                }
+                self.read_buf = line.into();
+                Ok(self.read_buf.pop_front())
+            }
+        }
+    }
+
+    // checks the limits that apply to every instruction, before running it
+    fn check_fuel(&self) -> Result<(), Interrupt> {
+        if self.output_pending {
+            return Err(Yield::Output.into());
+        }
+        // looking at the clock takes much longer than running an instruction
+        if let Some(deadline) = self.limits.deadline {
+            if self.executed & 1023 == 0 && Instant::now() >= deadline {
+                return Err(Limit::Deadline.into());
+            }
+        }
+        if self.limits.fuel == Some(0) {
+            return Err(Limit::Fuel.into());
+        }
+        Ok(())
+    }
+
+    // uses up fuel for an instruction that ran
+    fn burn_fuel(&mut self) {
+        self.executed += 1;
+        if let Some(fuel) = &mut self.limits.fuel {
+            *fuel -= 1;
+        }
+    }
+
+    // what has to hold before the pointer moves by `delta`, or an instruction changes the cell
+    // that far away
+    fn check_move(&self, delta: isize) -> Result<(), Interrupt> {
+        if self.strict {
+            if let Some(position) = self.tape.leaves_tape(delta) {
+                return Err(TrapKind::PointerOutOfBounds { position }.into());
+            }
+        }
+        if let Some(max) = self.limits.max_tape {
+            if self.tape.extent_after_move(delta) > max {
+                return Err(Limit::Tape.into());
+            }
+        }
+        Ok(())
+    }
+
+    fn move_by(&mut self, delta: isize) -> Result<(), Interrupt> {
+        self.check_move(delta)?;
+        self.tape.move_by(delta).map_err(RuntimeError::Tape)?;
+        Ok(())
+    }
+
+    fn add(&mut self, n: usize) -> Result<(), Interrupt> {
+        let value = self.added(self.tape.get(), n)?;
+        self.tape.set(value);
+        Ok(())
+    }
+
+    fn added(&self, value: C, n: usize) -> Result<C, Interrupt> {
+        if self.strict {
+            let max = C::WIDTH.max_value();
+            if n as u64 > (max - value.to_u32()) as u64 {
+                return Err(TrapKind::CellOverflow { value: value.to_u32(), added: n, max }.into());
+            }
+        }
+        Ok(value.wrapping_add_usize(n))
+    }
+
+    fn sub(&mut self, n: usize) -> Result<(), Interrupt> {
+        let value = self.subtracted(self.tape.get(), n)?;
+        self.tape.set(value);
+        Ok(())
+    }
+
+    fn subtracted(&self, value: C, n: usize) -> Result<C, Interrupt> {
+        if self.strict && n as u64 > value.to_u32() as u64 {
+            return Err(TrapKind::CellUnderflow { value: value.to_u32(), subtracted: n }.into());
+        }
+        Ok(value.wrapping_sub_usize(n))
+    }
+
+    // changes the cell at `offset` without moving the pointer. Off the tape, that cell is where
+    // moving the pointer there would take it, growing the tape or failing in the same way.
+    fn at(&mut self, offset: isize, f: impl FnOnce(&Self, C) -> Result<C, Interrupt>) -> Result<(), Interrupt> {
+        self.check_move(offset)?;
+        let position = self.tape.reach(offset).map_err(RuntimeError::Tape)?;
+        let value = f(self, self.tape.get_at(position).unwrap_or_default())?;
+        self.tape.set_at(position, value);
+        Ok(())
+    }
+
+    // changes all targets or none of them, so that resuming after a limit or an error doesn't
+    // add to a cell twice
+    fn multiply_add(&mut self, targets: &[(isize, isize)]) -> Result<(), Interrupt> {
+        let value = self.tape.get().to_u32() as usize;
+        if value == 0 {
+            return Ok(());
+        }
+
+        let mut changes: Vec<(isize, C)> = Vec::with_capacity(targets.len());
+        for &(offset, factor) in targets {
+            self.check_move(offset)?;
+            let position = self.tape.reach(offset).map_err(RuntimeError::Tape)?;
+            // wrapping is exact for cells, but strict mode has to see the real sum
+            let n = if self.strict {
+                factor.unsigned_abs().saturating_mul(value)
+            } else {
+                factor.unsigned_abs().wrapping_mul(value)
+            };
+            // on a tape that wraps around, two offsets can be the same cell
+            let old = match changes.iter().rev().find(|(i, _)| *i == position) {
+                Some((_, changed)) => *changed,
+                None => self.tape.get_at(position).unwrap_or_default(),
+            };
+            let new = if factor < 0 { self.subtracted(old, n)? } else { self.added(old, n)? };
+            changes.push((position, new));
+        }
+        for (position, value) in changes {
+            self.tape.set_at(position, value);
+        }
+        self.tape.set(C::default());
+        Ok(())
+    }
+
+    fn scan(&mut self, stride: isize) -> Result<(), Interrupt> {
+        // moving off the tape follows the edge policy, one step at a time. Every step counts as
+        // an instruction, so scanning around a wrapping tape without zeros runs out of fuel.
+        while !self.tape.scan(stride) {
+            self.check_fuel()?;
+            self.move_by(stride)?;
+            self.burn_fuel();
+        }
+        Ok(())
+    }
+
+    fn execute_instruction(&mut self, instruction: &DesugaredBrainFuckInstruction) -> Result<(), Interrupt> {
+        self.check_fuel()?;
+        match instruction {
+            DesugaredBrainFuckInstruction::Add(i) => self.add(*i)?,
+            DesugaredBrainFuckInstruction::Sub(i) => self.sub(*i)?,
+            DesugaredBrainFuckInstruction::Left(l) => self.move_by(-(*l as isize))?,
+            DesugaredBrainFuckInstruction::Right(r) => self.move_by(*r as isize)?,
+            DesugaredBrainFuckInstruction::Loop(_) => unreachable!("loops are executed by execute_loop"),
+            DesugaredBrainFuckInstruction::Zero => {
+                self.tape.set(C::default());
+            }
+            DesugaredBrainFuckInstruction::Set(v) => {
+                self.tape.set(C::from_byte(*v));
+            }
+            DesugaredBrainFuckInstruction::SetWide(v, _) => {
+                self.tape.set(C::from_u32(*v));
+            }
+            DesugaredBrainFuckInstruction::AddAt(offset, n) => self.at(*offset, |i, value| i.added(value, *n))?,
+            DesugaredBrainFuckInstruction::SubAt(offset, n) => self.at(*offset, |i, value| i.subtracted(value, *n))?,
+            DesugaredBrainFuckInstruction::SetAt(offset, v, _) => self.at(*offset, |_, _| Ok(C::from_u32(*v)))?,
+            DesugaredBrainFuckInstruction::MultiplyAdd(targets) => self.multiply_add(targets)?,
+            DesugaredBrainFuckInstruction::Scan { stride } => self.scan(*stride)?,
+            DesugaredBrainFuckInstruction::Input => self.input()?,
+            DesugaredBrainFuckInstruction::Output => self.output()?,
+            DesugaredBrainFuckInstruction::Write(bytes) => self.write(bytes)?,
+        }
+        self.burn_fuel();
+
+        Ok(())
+    }
+
+    fn input(&mut self) -> Result<(), Interrupt> {
+        if self.yielding && self.read_buf.is_empty() && !self.input_closed {
+            return Err(Yield::Input.into());
+        }
+        match (self.read_byte()?, self.eof) {
+            (Some(byte), _) => self.tape.set(C::from_byte(byte)),
+            (None, EofPolicy::Zero) => self.tape.set(C::default()),
+            (None, EofPolicy::MinusOne) => self.tape.set(C::from_u32(u32::MAX)),
+            (None, EofPolicy::Unchanged) => {}
+            (None, EofPolicy::Error) => return Err(RuntimeError::EndOfInput.into()),
+        }
+        Ok(())
+    }
+
+    fn output(&mut self) -> Result<(), Interrupt> {
+        if matches!(self.limits.max_output, Some(max) if self.written >= max) {
+            return Err(Limit::Output.into());
+        }
+        let byte = self.tape.get().to_byte();
+        self.output.write_all(&[byte]).map_err(RuntimeError::Output)?;
+        self.written += 1;
+        self.output_pending = self.yielding;
+        Ok(())
+    }
+
+    // writes all bytes or none of them, so that resuming doesn't write any of them twice
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Interrupt> {
+        if matches!(self.limits.max_output, Some(max) if self.written + bytes.len() as u64 > max) {
+            return Err(Limit::Output.into());
+        }
+        self.output.write_all(bytes).map_err(RuntimeError::Output)?;
+        self.written += bytes.len() as u64;
+        self.output_pending = self.yielding;
+        self.tape.set(C::default());
+        Ok(())
+    }
+
+    // `resume` is where to continue inside the body, or empty to start the loop from the top
+    fn execute_loop(&mut self, body: &[DesugaredBrainFuckInstruction], resume: &[usize], mut counter: Option<&mut Counter>) -> Result<(), Interrupt> {
+        if resume.is_empty() {
+            // stopping before the first check stops at the loop itself
+            self.check_fuel()?;
+            self.burn_fuel();
+            if let Some(counter) = counter.as_deref_mut() {
+                counter.executed += 1;
+            }
+            if self.tape.get().is_zero() {
+                return Ok(());
+            }
+            if let Some(counter) = counter.as_deref_mut() {
+                counter.iterations += 1;
+            }
+        }
+        self.execute_block(body, resume, counter.as_deref_mut().map(|i| &mut i.body[..]))?;
+
+        loop {
+            // stopping before checking again stops at the end of the body
+            self.check_fuel().map_err(|e| e.at(body.len()))?;
+            self.burn_fuel();
+            if self.tape.get().is_zero() {
+                return Ok(());
+            }
+            if let Some(counter) = counter.as_deref_mut() {
+                counter.iterations += 1;
+            }
+            self.execute_block(body, &[], counter.as_deref_mut().map(|i| &mut i.body[..]))?;
+        }
+    }
+
+    fn execute_block(&mut self, block: &[DesugaredBrainFuckInstruction], resume: &[usize], mut counters: Option<&mut [Counter]>) -> Result<(), Interrupt> {
+        let (start, mut inner) = match resume.split_first() {
+            Some((start, inner)) => (*start, inner),
+            None => (0, &[][..]),
+        };
+
+        for (idx, instruction) in block.iter().enumerate().skip(start) {
+            let counter = counters.as_deref_mut().map(|i| &mut i[idx]);
+            let res = match instruction {
+                DesugaredBrainFuckInstruction::Loop(body) => self.execute_loop(body, std::mem::take(&mut inner), counter),
+                i => self.execute_instruction(i).map(|()| {
+                    if let Some(counter) = counter {
+                        counter.executed += 1;
                     }
+                }),
+            };
+            res.map_err(|e| e.at(idx))?;
+        }
+
+        Ok(())
+    }
+
+    // runs ops until the end of the program, leaving `pc` at the op that stopped it
+    fn execute_ops(&mut self, program: &Bytecode, pc: &mut usize) -> Result<(), Interrupt> {
+        let ops = program.ops();
+        // without fuel or a deadline there is nothing to check before every op
+        let checked = self.limits.fuel.is_some() || self.limits.deadline.is_some();
+        while let Some(op) = ops.get(*pc) {
+            if checked {
+                self.check_fuel()?;
+            }
+            let next = match *op {
+                Op::Add(n) => {
+                    self.add(n)?;
+                    *pc + 1
+                }
+                Op::Sub(n) => {
+                    self.sub(n)?;
+                    *pc + 1
+                }
+                Op::Move(delta) => {
+                    self.move_by(delta)?;
+                    *pc + 1
+                }
+                Op::Set(v) => {
+                    self.tape.set(C::from_u32(v));
+                    *pc + 1
+                }
+                Op::AddAt(offset, n) => {
+                    self.at(offset, |i, value| i.added(value, n))?;
+                    *pc + 1
                 }
-                DesugaredBrainFuckInstruction::Output => {
-                    let byte = self.memory[self.ptr];
-                    if let Err(e) = self.output.write(&[byte]) {
-                        panic!("{e}")
+                Op::SubAt(offset, n) => {
+                    self.at(offset, |i, value| i.subtracted(value, n))?;
+                    *pc + 1
+                }
+                Op::SetAt(offset, v) => {
+                    self.at(offset, |_, _| Ok(C::from_u32(v)))?;
+                    *pc + 1
+                }
+                Op::MultiplyAdd(idx) => {
+                    self.multiply_add(program.targets(idx))?;
+                    *pc + 1
+                }
+                Op::Scan(stride) => {
+                    self.scan(stride)?;
+                    *pc + 1
+                }
+                Op::Input => {
+                    self.input()?;
+                    *pc + 1
+                }
+                Op::Output => {
+                    self.output()?;
+                    *pc + 1
+                }
+                Op::Write(idx) => {
+                    self.write(program.bytes(idx))?;
+                    *pc + 1
+                }
+                Op::JumpIfZero(target) if self.tape.get().is_zero() => target,
+                Op::JumpIfNotZero(target) if !self.tape.get().is_zero() => target,
+                Op::JumpIfZero(_) | Op::JumpIfNotZero(_) => *pc + 1,
+            };
+            self.burn_fuel();
+            *pc = next;
+        }
+
+        Ok(())
+    }
+
+    /// Runs a program with the bytecode engine, which is faster than [`execute`](Self::execute)
+    /// and writes exactly the same output. Programs that stop can't be resumed, and aren't
+    /// profiled.
+    pub fn execute_bytecode(&mut self, program: &Bytecode) -> Result<ExecutionSummary, RuntimeError> {
+        self.executed = 0;
+        self.paused = None;
+
+        let mut pc = 0;
+        let res = self.execute_ops(program, &mut pc).map_err(|Interrupt { reason, .. }| {
+            let pc = program.pc(pc).cloned().unwrap_or_default();
+            self.stopped(reason, pc, program.source_map())
+        });
+        let flushed = self.output.flush().map_err(RuntimeError::Output);
+        res.and(flushed)?;
+
+        Ok(self.summary())
+    }
+
+    fn stopped(&self, reason: Stop, pc: ProgramCounter, source_map: Option<&SourceMap>) -> RuntimeError {
+        match reason {
+            Stop::Trap(kind) => RuntimeError::Trap(Box::new(Trap {
+                kind,
+                span: source_map.and_then(|i| i.span(&pc)),
+                pc,
+                ptr: self.tape.ptr(),
+                tape: self.tape.window(8).to_string(),
+            })),
+            Stop::Limit(limit) => RuntimeError::Limit(LimitExceeded {
+                limit,
+                pc,
+                ptr: self.tape.ptr(),
+                instructions: self.executed,
+            }),
+            Stop::Error(e) => e,
+            Stop::Yield(_) => unreachable!("yields don't stop the program"),
+        }
+    }
+
+    pub fn execute(&mut self, program: DesugaredBrainFuckProgram) -> Result<ExecutionSummary, RuntimeError> {
+        Self::finished(self.start(program))
+    }
+
+    pub(crate) fn start(&mut self, program: DesugaredBrainFuckProgram) -> Result<Progress, RuntimeError> {
+        self.executed = 0;
+        self.output_pending = false;
+        self.counters = self.profiling.then(|| Counter::for_block(program.as_slice()));
+        self.run(program, ProgramCounter::default())
+    }
+
+    /// Continues the program that stopped with an error, at the instruction that failed. The
+    /// summary counts the instructions from the start of [`execute`](Self::execute). Does nothing
+    /// if the last program ran to the end.
+    pub fn resume(&mut self) -> Result<ExecutionSummary, RuntimeError> {
+        Self::finished(self.proceed())
+    }
+
+    pub(crate) fn proceed(&mut self) -> Result<Progress, RuntimeError> {
+        match self.paused.take() {
+            Some((program, pc)) => self.run(program, pc),
+            None => Ok(Progress::Finished(self.summary())),
+        }
+    }
+
+    fn finished(progress: Result<Progress, RuntimeError>) -> Result<ExecutionSummary, RuntimeError> {
+        match progress? {
+            Progress::Finished(summary) => Ok(summary),
+            Progress::Yielded(_) => unreachable!("only machines yield"),
+        }
+    }
+
+    /// Makes `,` and `.` stop the program instead of blocking. See [`Machine`](crate::machine::Machine).
+    pub(crate) fn yield_io(&mut self) {
+        self.yielding = true;
+    }
+
+    pub(crate) fn push_input(&mut self, bytes: &[u8]) {
+        self.read_buf.extend(bytes);
+    }
+
+    pub(crate) fn close_input(&mut self) {
+        self.input_closed = true;
+    }
+
+    /// Where the program that stopped with an error would continue.
+    pub fn paused_at(&self) -> Option<&ProgramCounter> {
+        self.paused.as_ref().map(|(_, pc)| pc)
+    }
+
+    /// The complete state of the interpreter: the tape, input that was read but not used yet,
+    /// and where the program that stopped with an error would continue.
+    pub fn snapshot(&self) -> Snapshot<C> {
+        let mut input: Vec<u8> = self.read_buf.iter().copied().collect();
+        input.extend_from_slice(self.input.buffer());
+
+        Snapshot {
+            tape: self.tape.clone(),
+            input,
+            paused: self.paused.as_ref().map(|(program, pc)| (fingerprint(program), pc.clone())),
+            executed: self.executed,
+            written: self.written,
+        }
+    }
+
+    /// Replaces the state of the interpreter with a snapshot. If a program was stopped when the
+    /// snapshot was taken, it continues with [`resume`](Self::resume), and `program` has to be
+    /// that same program. The configuration of the interpreter, except for the tape, is kept.
+    ///
+    /// Input the interpreter had already buffered is dropped in favour of the input in the
+    /// snapshot, which includes it if the snapshot came from this interpreter.
+    pub fn restore(&mut self, snapshot: Snapshot<C>, program: DesugaredBrainFuckProgram) -> Result<(), SnapshotError> {
+        let paused = match snapshot.paused {
+            Some((hash, pc)) if hash == fingerprint(&program) => {
+                if !points_into(&program, &pc) {
+                    return Err(SnapshotError::Corrupt("the program counter is outside of the program"));
+                }
+                // the profile only covers what runs after restoring
+                self.counters = self.profiling.then(|| Counter::for_block(program.as_slice()));
+                Some((program, pc))
+            }
+            Some(_) => return Err(SnapshotError::ProgramMismatch),
+            None => None,
+        };
+
+        self.tape = snapshot.tape;
+        let buffered = self.input.buffer().len();
+        self.input.consume(buffered);
+        self.read_buf = snapshot.input.into();
+        self.executed = snapshot.executed;
+        self.written = snapshot.written;
+        self.paused = paused;
+        Ok(())
+    }
+
+    /// How often every instruction of the last program ran, if profiling is enabled in the
+    /// [`InterpreterConfig`]. It's updated whenever the program stops, so it also covers
+    /// programs that stopped with an error.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Changes the limits, for example to raise the one that stopped a program before resuming it.
+    pub fn limits_mut(&mut self) -> &mut Limits {
+        &mut self.limits
+    }
+
+    /// Allows `fuel` more instructions to run. Does nothing if fuel isn't limited.
+    pub fn add_fuel(&mut self, fuel: u64) {
+        if let Some(i) = &mut self.limits.fuel {
+            *i = i.saturating_add(fuel);
+        }
+    }
+
+    fn summary(&self) -> ExecutionSummary {
+        ExecutionSummary {
+            instructions: self.executed,
+            ptr: self.tape.ptr(),
+        }
+    }
+
+    fn run(&mut self, program: DesugaredBrainFuckProgram, pc: ProgramCounter) -> Result<Progress, RuntimeError> {
+        let mut counters = self.counters.take();
+        let res = self.execute_block(program.as_slice(), pc.path(), counters.as_deref_mut());
+        if let Some(counters) = &counters {
+            self.profile = Some(Profile::new(&program, counters));
+        }
+        self.counters = counters;
+
+        let res = match res {
+            Ok(()) => Ok(None),
+            Err(Interrupt { reason, mut path }) => {
+                path.reverse();
+                let pc = ProgramCounter::new(path);
+                let res = match reason {
+                    Stop::Yield(reason) => {
+                        self.output_pending = false;
+                        Ok(Some(reason))
                     }
+                    reason => Err(self.stopped(reason, pc.clone(), program.source_map())),
+                };
+                self.paused = Some((program, pc));
+                res
+            }
+        };
+        // flush explicitly, even after an error, since errors in the implicit flush when
+        // dropping the BufWriter are lost
+        let flushed = self.output.flush().map_err(RuntimeError::Output);
+        let yielded = res?;
+        flushed?;
+
+        Ok(match yielded {
+            Some(reason) => Progress::Yielded(reason),
+            None => Progress::Finished(self.summary()),
+        })
+    }
+}
+
+impl<R: Read, C: Cell> BrainFuckInterpreter<Vec<u8>, R, C> {
+    // everything written since the last call
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(self.output.get_mut())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{empty, ErrorKind, Read, Write};
+    use std::rc::Rc;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+    use crate::interpreter::{BrainFuckInterpreter, EofPolicy, ExecutionSummary, InputMode, InterpreterConfig, RuntimeError};
+
+    struct Broken;
+
+    impl Write for Broken {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    impl Read for Broken {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    #[test]
+    fn summary() {
+        let mut output = Vec::new();
+        let summary = BrainFuckInterpreter::new(&mut output, empty())
+            .execute(program("++[->+<]>>"))
+            .unwrap();
+
+        // add, loop checked 3 times, 2 iterations of 4 instructions, right
+        assert_eq!(summary, ExecutionSummary { instructions: 1 + 3 + 2 * 4 + 1, ptr: 2 });
+    }
+
+    #[test]
+    fn scans() {
+        use crate::desugared_brainfuck::DesugaredBrainFuckInstruction::{self, Loop, Right, Left, Scan};
+        use crate::optimize::scans;
+        use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+        // only the optimizer turns loops into scans, which run fewer instructions
+        assert_eq!(program("[>]").as_slice(), [Loop(vec![Right(1)])]);
+        let program = |code: &str| scans(&program(code));
+        assert_eq!(program("+[>>]>[<]").as_slice(), [DesugaredBrainFuckInstruction::Add(1), Scan { stride: 2 }, Right(1), Scan { stride: -1 }]);
+        assert_eq!(program("[>>]<[<]").resugar().to_string(), "[>>]<[<]\n");
+
+        // the same as running the loops, at every edge of the tape
+        let fill = "+>+>+>>+>+>>+<<<";
+        for kind in [TapeKind::Fixed(8), TapeKind::GrowRight(2), TapeKind::Infinite] {
+            for edges in [EdgePolicy::Wrap, EdgePolicy::Error] {
+                let config = InterpreterConfig { tape: TapeConfig::new(kind, edges).unwrap(), ..Default::default() };
+                for scan in [">[>>]+", "[<]+", "<<[<<<]+", ">[>]<+"] {
+                    let run = |loops: bool| {
+                        let mut instructions = program(fill).as_slice().to_vec();
+                        for i in program(scan).as_slice() {
+                            instructions.push(match i {
+                                Scan { stride } if loops && *stride < 0 => Loop(vec![Left(stride.unsigned_abs())]),
+                                Scan { stride } if loops => Loop(vec![Right(*stride as usize)]),
+                                i => i.clone(),
+                            });
+                        }
+                        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+                        let res = interpreter.execute(DesugaredBrainFuckProgram::from_instructions(instructions)).map(|i| i.ptr).map_err(|e| e.to_string());
+                        (res.map_err(|e| e.split(" at ").next().unwrap().to_string()), interpreter.tape().bounds(), interpreter.tape().nonzero_cells())
+                    };
+                    assert_eq!(run(true), run(false), "{scan} on {kind:?} with {edges:?}");
                 }
             }
         }
+
+        // around and around a tape without zeros
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Fixed(3), EdgePolicy::Wrap).unwrap(),
+            limits: crate::sandbox::Limits { fuel: Some(100), ..Default::default() },
+            ..Default::default()
+        };
+        let res = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config).execute(program("+>+>+[>]"));
+        assert!(matches!(res, Err(RuntimeError::Limit(_))));
+    }
+
+    #[test]
+    fn offsets_dont_move_the_pointer() {
+        use crate::desugared_brainfuck::DesugaredBrainFuckInstruction::{AddAt, SubAt};
+        use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+        // moving there and back would wrap to the right end, and then grow the tape past it
+        let config = InterpreterConfig { tape: TapeConfig::new(TapeKind::GrowRight(2), EdgePolicy::Wrap).unwrap(), ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let summary = interpreter.execute(DesugaredBrainFuckProgram::from_instructions(vec![AddAt(-1, 3), SubAt(3, 1)])).unwrap();
+        assert_eq!(summary.ptr, 0);
+        assert_eq!(interpreter.tape().bounds(), (0, 4));
+        assert_eq!(interpreter.tape().nonzero_cells(), [(1, 3), (3, 255)]);
+    }
+
+    #[test]
+    fn output_error() {
+        let res = BrainFuckInterpreter::new(Broken, empty()).execute(program("+."));
+        assert!(matches!(res, Err(RuntimeError::Output(e)) if e.kind() == ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn input_error() {
+        let res = BrainFuckInterpreter::new(Vec::new(), Broken).execute(program(",."));
+        assert!(matches!(res, Err(RuntimeError::Input(e)) if e.kind() == ErrorKind::BrokenPipe));
+    }
+
+    #[test]
+    fn wide_cells() {
+        // 256 only wraps around to zero in an 8 bit cell
+        let code = format!("{}[>{}.<[-]]", "+".repeat(256), "+".repeat(300));
+
+        let mut output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::<_, _, u16>::with_config(&mut output, empty(), InterpreterConfig::default());
+        interpreter.execute(program(&code)).unwrap();
+        assert_eq!(interpreter.tape().get_at(1), Some(300));
+        drop(interpreter);
+        // output writes the lowest byte of the cell
+        assert_eq!(output, [44]);
+
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, empty(), InterpreterConfig::default())
+            .execute(program(&code))
+            .unwrap();
+        assert!(output.is_empty());
+    }
+
+    fn run_with_eof(eof: EofPolicy, code: &str, input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+        let config = InterpreterConfig { eof, ..Default::default() };
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, input, config).execute(program(code))?;
+        Ok(output)
+    }
+
+    #[test]
+    fn eof_policies() {
+        // echo programs that stop at the end of the input, written for each convention
+        assert_eq!(run_with_eof(EofPolicy::Zero, ",[.,]", b"ab").unwrap(), b"ab");
+        assert_eq!(run_with_eof(EofPolicy::MinusOne, ",+[-.,+]", b"ab").unwrap(), b"ab");
+        assert_eq!(run_with_eof(EofPolicy::Unchanged, ",[.[-],]", b"ab").unwrap(), b"ab");
+
+        // reading past the end leaves a recognizable value
+        assert_eq!(run_with_eof(EofPolicy::Zero, "+++,.", b"").unwrap(), [0]);
+        assert_eq!(run_with_eof(EofPolicy::MinusOne, "+++,.", b"").unwrap(), [255]);
+        assert_eq!(run_with_eof(EofPolicy::Unchanged, "+++,.", b"").unwrap(), [3]);
+
+        // the program still gets to write what came before the error
+        let mut output = Vec::new();
+        let config = InterpreterConfig { eof: EofPolicy::Error, ..Default::default() };
+        let res = BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, &b"a"[..], config).execute(program(",.,.,."));
+        assert!(matches!(res, Err(RuntimeError::EndOfInput)));
+        assert_eq!(output, b"a");
+    }
+
+    #[test]
+    fn minus_one_fills_wide_cells() {
+        let config = InterpreterConfig { eof: EofPolicy::MinusOne, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u16>::with_config(Vec::new(), empty(), config);
+        interpreter.execute(program(",")).unwrap();
+        assert_eq!(interpreter.tape().get_at(0), Some(u16::MAX));
+    }
+
+    #[test]
+    fn raw_input() {
+        let input = [0xff, b'\n', 0, 0xc3];
+        let mut output = Vec::new();
+        BrainFuckInterpreter::new(&mut output, &input[..])
+            .execute(program(",.,.,.,.,."))
+            .unwrap();
+        // bytes that aren't utf-8 come through untouched, and the end of input reads as 0
+        assert_eq!(output, [0xff, b'\n', 0, 0xc3, 0]);
+    }
+
+    #[test]
+    fn line_input() {
+        let config = InterpreterConfig { input: InputMode::Line, ..Default::default() };
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, &b"a\nb"[..], config)
+            .execute(program(",.,.,.,.,."))
+            .unwrap();
+        assert_eq!(output, b"a\nb\n\0");
+    }
+
+    // records everything that was flushed, so reads can check what the user got to see
+    #[derive(Clone, Default)]
+    struct Terminal {
+        shown: Rc<RefCell<Vec<u8>>>,
+        pending: Vec<u8>,
+    }
+
+    impl Write for Terminal {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            self.shown.borrow_mut().append(&mut self.pending);
+            Ok(())
+        }
+    }
+
+    struct User(Rc<RefCell<Vec<u8>>>);
+
+    impl Read for User {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            assert_eq!(*self.0.borrow(), b"?", "the prompt should be flushed before reading");
+            buf[0] = b'y';
+            Ok(1)
+        }
     }
 
-    pub fn execute(&mut self, program: DesugaredBrainFuckProgram) {
-        self.execute_internal(program.as_slice().iter())
+    #[test]
+    fn flush_before_reading() {
+        let terminal = Terminal::default();
+        let user = User(terminal.shown.clone());
+        BrainFuckInterpreter::new(terminal.clone(), user)
+            .execute(program(&format!("{}.,", "+".repeat(b'?' as usize))))
+            .unwrap();
     }
-}
\ No newline at end of file
+}
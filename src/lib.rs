@@ -0,0 +1,35 @@
+//! A brainfuck toolchain: a parser and desugarer for brainfuck, a compiler from the low level
+//! intermediate language (LIL) to brainfuck, and an interpreter to run the result.
+
+pub mod brainfuck;
+pub mod bytecode;
+pub mod cell;
+pub mod coverage;
+pub mod debugger;
+pub mod desugared_brainfuck;
+pub mod emit;
+pub mod interpreter;
+pub mod low_intermediate;
+pub mod machine;
+pub mod optimize;
+mod parser;
+pub mod profile;
+pub mod sandbox;
+pub mod snapshot;
+pub mod source;
+pub mod strict;
+pub mod tape;
+
+pub use brainfuck::{BrainFuckProgram, UnbalancedLoop, UnbalancedLoops};
+pub use bytecode::Bytecode;
+pub use cell::{Cell, CellWidth};
+pub use coverage::Coverage;
+pub use desugared_brainfuck::{DesugaredBrainFuckProgram, ProgramCounter, SourceMap};
+pub use interpreter::{BrainFuckInterpreter, EofPolicy, ExecutionSummary, InputMode, InterpreterConfig, RuntimeError};
+pub use low_intermediate::{LilSession, LowLevelIntermediateProgram};
+pub use machine::{Machine, Status};
+pub use parser::{Comment, CommentKind, Diagnostic, ParseError};
+pub use profile::Profile;
+pub use sandbox::{Limit, LimitExceeded, Limits};
+pub use snapshot::{Snapshot, SnapshotError};
+pub use strict::{Trap, TrapKind};
@@ -1,14 +1,17 @@
 use std::collections::{HashMap, HashSet};
-use std::convert::Infallible;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
-use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram};
-use crate::parser::Parser;
+use crate::cell::CellWidth;
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, SourceEntry, SourceMap};
+use crate::parser::{Comment, Diagnostic, ParseError, Parser};
+use crate::source::Span;
 
 pub type Variable = usize;
 
 pub enum LowLevelIntermediateExpr {
     Const(Variable, u8),
+    /// a constant that only fits in cells wider than 8 bits
+    ConstWide(Variable, u32),
     Copy {
         dest: Variable,
         src: Variable,
@@ -25,13 +28,14 @@ impl LowLevelIntermediateExpr {
         write!(f, "{:level$}", "", level = depth * 4)?;
         match self {
             LowLevelIntermediateExpr::Const(var, val) => write!(f, "v{var} = {val};"),
+            LowLevelIntermediateExpr::ConstWide(var, val) => write!(f, "v{var} = {val};"),
             LowLevelIntermediateExpr::Copy { dest, src } => write!(f, "v{dest} = v{src};"),
             LowLevelIntermediateExpr::AddAssign { dest, modifier } => write!(f, "v{dest} += v{modifier};"),
             LowLevelIntermediateExpr::SubAssign { dest, modifier } => write!(f, "v{dest} -= v{modifier};"),
             LowLevelIntermediateExpr::Print(v) => write!(f, "print v{v};"),
             LowLevelIntermediateExpr::Input(v) => write!(f, "input v{v};"),
             LowLevelIntermediateExpr::WhileNotZero(var, block) => {
-                write!(f, "while v{var} != 0 {{\n")?;
+                writeln!(f, "while v{var} != 0 {{")?;
                 LowLevelIntermediateProgram::fmt_block(f, block, depth + 1)?;
                 write!(f, "}}")
             }
@@ -48,25 +52,42 @@ impl Display for LowLevelIntermediateExpr {
 
 pub struct LowLevelIntermediateProgram {
     program: Vec<LowLevelIntermediateExpr>,
+    // where every statement is in the source, in the same shape as `program`
+    statements: SourceMap,
+    comments: Vec<Comment>,
+    // the cells the program is written for
+    width: CellWidth,
 }
 
+#[derive(Clone)]
 struct CompileState {
     data_ptr: usize,
     used: HashSet<Variable>,
     free_temps: HashSet<Variable>,
     smallest_unused: usize,
+    width: CellWidth,
 }
 
 impl CompileState {
+    pub fn new(width: CellWidth) -> Self {
+        Self {
+            data_ptr: 0,
+            used: Default::default(),
+            free_temps: Default::default(),
+            smallest_unused: 0,
+            width,
+        }
+    }
+
     pub fn move_to(&mut self, to: Variable) -> DesugaredBrainFuckInstruction {
         if to > self.data_ptr {
-            let lefts = to - self.data_ptr;
+            let rights = to - self.data_ptr;
             self.data_ptr = to;
-            DesugaredBrainFuckInstruction::Left(lefts)
+            DesugaredBrainFuckInstruction::Right(rights)
         } else {
-            let rights = self.data_ptr - to;
+            let lefts = self.data_ptr - to;
             self.data_ptr = to;
-            DesugaredBrainFuckInstruction::Right(rights)
+            DesugaredBrainFuckInstruction::Left(lefts)
         }
     }
 
@@ -75,6 +96,8 @@ impl CompileState {
             self.smallest_unused = variable + 1;
         }
 
+        // a variable introduced after compilation started may reuse a freed temp, which is zero again
+        self.free_temps.remove(&variable);
         self.used.insert(variable);
     }
 
@@ -108,15 +131,28 @@ impl CompileState {
 }
 
 impl LowLevelIntermediateProgram {
-    fn compile_iter<'a>(program: impl Iterator<Item=&'a LowLevelIntermediateExpr>, state: &mut CompileState) -> Vec<DesugaredBrainFuckInstruction> {
+    // compiles statements, and maps the generated instructions back to the statement they came from
+    fn compile_iter(program: &[LowLevelIntermediateExpr], statements: &[SourceEntry], state: &mut CompileState) -> (Vec<DesugaredBrainFuckInstruction>, Vec<SourceEntry>) {
         let mut res = Vec::new();
+        let mut entries = Vec::new();
 
-        for expr in program {
+        for (expr, statement) in program.iter().zip(statements) {
+            let start = res.len();
             match expr {
                 LowLevelIntermediateExpr::Const(var, val) => {
                     assert!(state.used(var));
                     res.push(state.move_to(*var));
-                    res.push(DesugaredBrainFuckInstruction::Set(*val));
+                    if state.width == CellWidth::U8 {
+                        res.push(DesugaredBrainFuckInstruction::Set(*val));
+                    } else {
+                        // resugaring `Set` relies on 8 bit cells wrapping around
+                        res.push(DesugaredBrainFuckInstruction::SetWide(*val as u32, state.width));
+                    }
+                }
+                LowLevelIntermediateExpr::ConstWide(var, val) => {
+                    assert!(state.used(var));
+                    res.push(state.move_to(*var));
+                    res.push(DesugaredBrainFuckInstruction::SetWide(*val, state.width));
                 }
                 LowLevelIntermediateExpr::AddAssign { dest: source, modifier } => {
                     assert!(state.used(source));
@@ -194,12 +230,22 @@ impl LowLevelIntermediateProgram {
                 }
                 LowLevelIntermediateExpr::WhileNotZero(v, code) => {
                     res.push(state.move_to(*v));
+                    let mut body = Vec::new();
                     res.push(state.create_loop(|state| {
-                        let mut inner = Vec::new();
-                        inner.extend(Self::compile_iter(code.iter(), state));
+                        let (mut inner, inner_entries) = Self::compile_iter(code, &statement.body, state);
+                        body = inner_entries;
                         inner.push(state.move_to(*v));
+                        body.extend(Self::map_to(statement.span, &inner[inner.len() - 1..]));
                         inner
                     }));
+
+                    entries.extend(Self::map_to(statement.span, &res[start..start + 1]));
+                    entries.push(SourceEntry {
+                        span: statement.span,
+                        end: statement.end,
+                        body,
+                    });
+                    continue;
                 }
                 LowLevelIntermediateExpr::Copy { src, dest } => {
                     assert!(state.used(src));
@@ -238,15 +284,38 @@ impl LowLevelIntermediateProgram {
                     state.free_temp(temp0);
                 }
             }
+            entries.extend(Self::map_to(statement.span, &res[start..]));
         }
 
-        res
+        (res, entries)
+    }
+
+    // source entries that point every instruction, including those in loops, at `span`
+    fn map_to(span: Span, instructions: &[DesugaredBrainFuckInstruction]) -> Vec<SourceEntry> {
+        instructions.iter()
+            .map(|i| match i {
+                DesugaredBrainFuckInstruction::Loop(body) => SourceEntry {
+                    span,
+                    end: Some(span),
+                    body: Self::map_to(span, body),
+                },
+                _ => SourceEntry {
+                    span,
+                    end: None,
+                    body: Vec::new(),
+                },
+            })
+            .collect()
+    }
+
+    fn source_map(&self, entries: Vec<SourceEntry>) -> SourceMap {
+        SourceMap::new(entries, self.statements.source().map(str::to_string))
     }
 
     fn allocate_variables<'a>(program: impl Iterator<Item=&'a LowLevelIntermediateExpr>, state: &mut CompileState) {
         for i in program {
             match i {
-                LowLevelIntermediateExpr::Const(v, _) => {
+                LowLevelIntermediateExpr::Const(v, _) | LowLevelIntermediateExpr::ConstWide(v, _) => {
                     state.mark_used(*v);
                 }
                 LowLevelIntermediateExpr::Input(v) => {
@@ -261,167 +330,196 @@ impl LowLevelIntermediateProgram {
     }
 
     pub fn compile(&self) -> DesugaredBrainFuckProgram {
-        let mut state = CompileState {
-            data_ptr: 0,
-            used: Default::default(),
-            free_temps: Default::default(),
-            smallest_unused: 0,
-        };
+        let mut state = CompileState::new(self.width);
 
         Self::allocate_variables(self.program.iter(), &mut state);
-        DesugaredBrainFuckProgram::from_instructions(Self::compile_iter(
-            self.program.iter(),
-            &mut state,
-        ))
+        let (instructions, entries) = Self::compile_iter(&self.program, self.statements.entries(), &mut state);
+        DesugaredBrainFuckProgram::from_instructions(instructions).with_source_map(self.source_map(entries))
     }
 
-    pub fn parse_expr(s: &mut Parser, alloc: &mut VariableAllocator) -> LowLevelIntermediateExpr {
-        if s.accept_str("print").is_some() {
-            s.whitespace();
-            let Some(name) = s.parse_ident() else {
-                panic!("expected variable name after 'print'");
-            };
-            s.whitespace();
-            assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-            s.whitespace();
-
-            let var = alloc.variable(name);
-            return LowLevelIntermediateExpr::Print(var)
-        }
+    fn parse_variable(s: &mut Parser, alloc: &mut VariableAllocator) -> Result<Variable, Diagnostic> {
+        s.whitespace();
+        let Some(name) = s.parse_ident() else {
+            return Err(s.error(&["variable name"]));
+        };
 
-        if s.accept_str("input").is_some() {
-            s.whitespace();
-            let Some(name) = s.parse_ident() else {
-                panic!("expected variable name after 'input'");
-            };
-            s.whitespace();
-            assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-            s.whitespace();
+        Ok(alloc.variable(name))
+    }
 
-            let var = alloc.variable(name);
-            return LowLevelIntermediateExpr::Input(var)
+    fn expect(s: &mut Parser, token: &str) -> Result<(), Diagnostic> {
+        s.whitespace();
+        if s.accept_str(token).is_none() {
+            return Err(s.error(&[&format!("'{token}'")]));
         }
 
-        if s.accept_str("while").is_some() {
-            s.whitespace();
-            let Some(name) = s.parse_ident() else {
-                panic!("expected variable name after 'while' at {s}");
-            };
-
-            s.whitespace();
-            if s.accept_str("!=").is_none() {
-                panic!("expected '!=' name after 'while {}' at {s}", name);
-            }
-            s.whitespace();
-            if s.accept_str("0").is_none() {
-                panic!("expected '0' name after 'while {} !=' at {s}", name);
-            }
-            s.whitespace();
+        Ok(())
+    }
 
-            if s.accept_str("{").is_none() {
-                panic!("expected '{{' name after 'while {} != 0' at {s}", name);
-            }
-            s.whitespace();
+    // parses a statement, and returns it with where it is in the source
+    fn parse_statement(s: &mut Parser, alloc: &mut VariableAllocator, width: CellWidth, diagnostics: &mut Vec<Diagnostic>) -> Result<(LowLevelIntermediateExpr, SourceEntry), Diagnostic> {
+        s.whitespace();
+        let start = s.position();
+        if s.accept_keyword("while").is_some() {
+            let var = Self::parse_variable(s, alloc)?;
+            Self::expect(s, "!=")?;
+            Self::expect(s, "0")?;
+            Self::expect(s, "{")?;
+
+            let (block, body) = Self::parse_block(s, alloc, width, diagnostics, true)?;
+            let close = s.position();
+            s.accept('}');
+            let entry = SourceEntry {
+                span: Span::new(start, s.position()),
+                end: Some(Span::new(close, s.position())),
+                body,
+            };
+            return Ok((LowLevelIntermediateExpr::WhileNotZero(var, block), entry));
+        }
 
-            let mut res = Vec::new();
-            loop {
-                s.whitespace();
-                if s.is_empty() {
-                    panic!("expected '}}'");
-                }
-                if s.accept('}').is_some() {
-                    break;
-                }
-                s.whitespace();
+        let expr = Self::parse_expr(s, alloc, width)?;
+        let entry = SourceEntry {
+            span: Span::new(start, s.position()),
+            end: None,
+            body: Vec::new(),
+        };
+        Ok((expr, entry))
+    }
 
-                res.push(Self::parse_expr(s, alloc));
-                s.whitespace();
-            }
+    fn parse_expr(s: &mut Parser, alloc: &mut VariableAllocator, width: CellWidth) -> Result<LowLevelIntermediateExpr, Diagnostic> {
+        if s.accept_keyword("print").is_some() {
+            let var = Self::parse_variable(s, alloc)?;
+            Self::expect(s, ";")?;
+            return Ok(LowLevelIntermediateExpr::Print(var));
+        }
 
-            let var = alloc.variable(name);
-            return LowLevelIntermediateExpr::WhileNotZero(var, res);
+        if s.accept_keyword("input").is_some() {
+            let var = Self::parse_variable(s, alloc)?;
+            Self::expect(s, ";")?;
+            return Ok(LowLevelIntermediateExpr::Input(var));
         }
 
-        s.whitespace();
         let Some(dest) = s.parse_ident() else {
-            panic!("expected variable name at {s}");
+            return Err(s.error(&["statement"]));
         };
         let dest = alloc.variable(dest);
 
         s.whitespace();
         if s.accept_str("+=").is_some() {
-            s.whitespace();
-            let Some(modifier) = s.parse_ident() else {
-                panic!("expected variable name after '+=' at {s}");
-            };
-            s.whitespace();
-            assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-            s.whitespace();
-
-            let modifier = alloc.variable(modifier);
-            return LowLevelIntermediateExpr::AddAssign {
+            let modifier = Self::parse_variable(s, alloc)?;
+            Self::expect(s, ";")?;
+            return Ok(LowLevelIntermediateExpr::AddAssign {
                 dest,
                 modifier,
-            }
+            });
         }
         if s.accept_str("-=").is_some() {
-            s.whitespace();
-            let Some(modifier) = s.parse_ident() else {
-                panic!("expected variable name after '-='");
-            };
-            s.whitespace();
-            assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-            s.whitespace();
-
-            let modifier = alloc.variable(modifier);
-            return LowLevelIntermediateExpr::SubAssign {
+            let modifier = Self::parse_variable(s, alloc)?;
+            Self::expect(s, ";")?;
+            return Ok(LowLevelIntermediateExpr::SubAssign {
                 dest,
                 modifier,
-            }
+            });
         }
         if s.accept_str("=").is_some() {
             s.whitespace();
-            if let Some(value) = s.parse_num::<u8>() {
-                s.whitespace();
-                assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-                s.whitespace();
-                return LowLevelIntermediateExpr::Const(dest, value)
+            if let Some(value) = s.parse_num(width.max_value()) {
+                Self::expect(s, ";")?;
+                return Ok(match u8::try_from(value) {
+                    Ok(value) => LowLevelIntermediateExpr::Const(dest, value),
+                    Err(_) => LowLevelIntermediateExpr::ConstWide(dest, value),
+                });
             } else if let Some(i) = s.parse_ident() {
-                s.whitespace();
-                assert!(s.accept(';').is_some(), "expected semicolon at the end of the line at {s}");
-                s.whitespace();
+                Self::expect(s, ";")?;
 
                 let src = alloc.variable(i);
-                return LowLevelIntermediateExpr::Copy {
+                return Ok(LowLevelIntermediateExpr::Copy {
                     dest,
                     src,
-                }
+                });
             } else {
-                panic!("expected numer (in 0..=255) or variable after '=' at {s}");
+                return Err(s.error(&[&format!("number in 0..={}", width.max_value()), "variable name"]));
             }
+        }
+
+        Err(s.error(&["'+='", "'-='", "'='"]))
+    }
 
+    // parses statements up to the end of the input, or up to the closing brace of a nested block.
+    // Errors in statements are collected in `diagnostics`, parsing resumes after them.
+    fn parse_block(s: &mut Parser, alloc: &mut VariableAllocator, width: CellWidth, diagnostics: &mut Vec<Diagnostic>, nested: bool) -> Result<(Vec<LowLevelIntermediateExpr>, Vec<SourceEntry>), Diagnostic> {
+        let mut res = Vec::new();
+        let mut entries = Vec::new();
+        loop {
+            s.whitespace();
+            match s.peek() {
+                None if nested => return Err(s.error(&["'}'"])),
+                None => break,
+                Some('}') if nested => break,
+                Some('}') => {
+                    diagnostics.push(s.error(&["statement"]));
+                    s.accept('}');
+                    continue;
+                }
+                _ => {}
+            }
 
+            match Self::parse_statement(s, alloc, width, diagnostics) {
+                Ok((i, entry)) => {
+                    res.push(i);
+                    entries.push(entry);
+                }
+                Err(e) => {
+                    diagnostics.push(e);
+                    s.recover();
+                }
+            }
         }
 
-        panic!("expected '+=', '-=' or '=' after variable at {s}");
+        Ok((res, entries))
     }
 
-    pub fn parse(s: &str) -> Self {
-        let mut variable_allocator = VariableAllocator::new();
+    pub fn parse(s: &str) -> Result<Self, ParseError> {
+        Self::parse_for(s, CellWidth::U8)
+    }
 
-        let mut res = Vec::new();
+    /// Parses a program for cells of the given width, which allows constants up to the largest
+    /// value such a cell can hold.
+    pub fn parse_for(s: &str, width: CellWidth) -> Result<Self, ParseError> {
+        Self::parse_with(s, &mut VariableAllocator::new(), width)
+    }
+
+    fn parse_with(s: &str, variable_allocator: &mut VariableAllocator, width: CellWidth) -> Result<Self, ParseError> {
+        let mut diagnostics = Vec::new();
         let mut stream = Parser::new(s);
-        stream.whitespace();
-        while !stream.is_empty() {
-            stream.whitespace();
-            res.push(Self::parse_expr(&mut stream, &mut variable_allocator));
-            stream.whitespace();
-        }
-        Self {
-            program: res,
+        let (program, entries) = match Self::parse_block(&mut stream, variable_allocator, width, &mut diagnostics, false) {
+            Ok(i) => i,
+            Err(e) => {
+                diagnostics.push(e);
+                Default::default()
+            }
+        };
+
+        let (comments, comment_errors) = stream.finish();
+        diagnostics.extend(comment_errors);
+        diagnostics.sort_by_key(|i| i.span.start);
+
+        if diagnostics.is_empty() {
+            Ok(Self {
+                program,
+                statements: SourceMap::new(entries, Some(s.to_string())),
+                comments,
+                width,
+            })
+        } else {
+            Err(ParseError::new(diagnostics))
         }
     }
 
+    /// The comments in the source this program was parsed from, in source order.
+    pub fn comments(&self) -> &[Comment] {
+        &self.comments
+    }
+
     fn fmt_block(f: &mut Formatter<'_>, block: &[LowLevelIntermediateExpr], depth: usize) -> std::fmt::Result {
         for i in block {
             i.fmt_expr(f, depth)?;
@@ -438,11 +536,18 @@ impl Display for LowLevelIntermediateProgram {
     }
 }
 
-pub struct VariableAllocator {
+#[derive(Clone)]
+struct VariableAllocator {
     vars: HashMap<String, usize>,
     max: usize,
 }
 
+impl Default for VariableAllocator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl VariableAllocator {
     pub fn new() -> Self {
         Self {
@@ -464,19 +569,65 @@ impl VariableAllocator {
     }
 }
 
+/// Compiles a program piece by piece, keeping variable names bound to the same cells between pieces.
+#[derive(Clone)]
+pub struct LilSession {
+    variables: VariableAllocator,
+    state: CompileState,
+}
+
+impl Default for LilSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LilSession {
+    pub fn new() -> Self {
+        Self::for_width(CellWidth::U8)
+    }
+
+    pub fn for_width(width: CellWidth) -> Self {
+        Self {
+            variables: VariableAllocator::new(),
+            state: CompileState::new(width),
+        }
+    }
+
+    pub fn parse(&mut self, s: &str) -> Result<LowLevelIntermediateProgram, ParseError> {
+        LowLevelIntermediateProgram::parse_with(s, &mut self.variables, self.state.width)
+    }
+
+    /// Compiles the next piece of the program. The generated code expects the data pointer to be
+    /// where the previous piece left it.
+    pub fn compile(&mut self, program: &LowLevelIntermediateProgram) -> DesugaredBrainFuckProgram {
+        // every cell a name ever got bound to is reserved, variables that were never assigned
+        // simply read as zero
+        for i in 0..self.variables.max {
+            self.state.mark_used(i);
+        }
+
+        let (instructions, entries) = LowLevelIntermediateProgram::compile_iter(&program.program, program.statements.entries(), &mut self.state);
+        DesugaredBrainFuckProgram::from_instructions(instructions).with_source_map(program.source_map(entries))
+    }
+}
+
 impl FromStr for LowLevelIntermediateProgram {
-    type Err = Infallible;
+    type Err = ParseError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self::parse(s))
+        Self::parse(s)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::{Cursor, stdin};
-    use crate::interpreter::BrainFuckInterpreter;
-    use crate::low_intermediate::LowLevelIntermediateProgram;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::cell::CellWidth;
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig};
+    use crate::low_intermediate::{LilSession, LowLevelIntermediateProgram};
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
 
     macro_rules! bf_test {
         ($name: ident: $inp: literal, $output: expr) => {
@@ -485,12 +636,12 @@ mod tests {
                 let program = $inp;
 
                 let mut output_buf = Cursor::new(Vec::new());
-                let interm = LowLevelIntermediateProgram::parse(program);
+                let interm = LowLevelIntermediateProgram::parse(program).unwrap();
                 let bf = interm.compile();
                 println!("code: {}", bf);
                 {
                     let mut interpreter = BrainFuckInterpreter::new(&mut output_buf, stdin());
-                    interpreter.execute(bf);
+                    interpreter.execute(bf).unwrap();
                 }
                 let output = output_buf.into_inner();
                 // println!("bytes: {:?}", output);
@@ -536,5 +687,109 @@ print res;
 "#,
         [4]
     );
+
+    #[test]
+    fn session_keeps_variables() {
+        let mut output_buf = Cursor::new(Vec::new());
+        {
+            let mut interpreter = BrainFuckInterpreter::new(&mut output_buf, stdin());
+            let mut session = LilSession::new();
+            for line in ["a = 3;", "b = 4; a += b;", "c = a; c += b;", "print a; print c;"] {
+                let program = session.parse(line).unwrap();
+                interpreter.execute(session.compile(&program)).unwrap();
+            }
+        }
+
+        assert_eq!(&[7, 11], output_buf.into_inner().as_slice())
+    }
+
+    #[test]
+    fn parse_errors_recover() {
+        let Err(e) = LowLevelIntermediateProgram::parse("a = 3 print a;\nwhile a != 0 {\n  a -= ;\n}\nb = 256;\nprint b;") else {
+            panic!("program should not parse")
+        };
+
+        let found: Vec<_> = e.diagnostics().iter()
+            .map(|i| (i.span.start.line, i.span.start.column, i.span.len(), i.found.as_str()))
+            .collect();
+        assert_eq!(found, [
+            (1, 7, 5, "'print'"),
+            (3, 8, 1, "';'"),
+            (5, 5, 3, "'256'"),
+        ]);
+        assert_eq!(e.diagnostics()[0].expected, ["';'"]);
+    }
+
+    bf_test!(
+        no_whitespace:
+        "one = 1;a = 3;b = 4;a += b;while a !=0{a -= one;b += one;}print b;",
+        [11]
+    );
+
+    #[test]
+    fn keywords_need_a_word_boundary() {
+        assert!(LowLevelIntermediateProgram::parse("printer = 1; print printer;").is_ok());
+        assert!(LowLevelIntermediateProgram::parse("whilex = 1;").is_ok());
+    }
+
+    #[test]
+    fn comments() {
+        let program = LowLevelIntermediateProgram::parse("
+// a counter
+a = 2; /* start /* nested */ here */ one = 1;
+while a != 0 { // loop
+    a -= one; /* ; } */
 }
+").unwrap();
+
+        let comments: Vec<_> = program.comments().iter()
+            .map(|i| (i.span.start.line, i.text.as_str()))
+            .collect();
+        assert_eq!(comments, [
+            (2, " a counter"),
+            (3, " start /* nested */ here "),
+            (4, " loop"),
+            (5, " ; } "),
+        ]);
+
+        let Err(e) = LowLevelIntermediateProgram::parse("a = 1; /* /* */") else {
+            panic!("unterminated comment should not parse")
+        };
+        assert_eq!(e.diagnostics().len(), 1);
+        assert_eq!(e.diagnostics()[0].span.start.column, 8);
+    }
+
+    #[test]
+    fn wide_constants() {
+        let code = "a = 1000; b = 3; a += b; print a;";
+        assert!(LowLevelIntermediateProgram::parse(code).is_err());
+
+        let program = LowLevelIntermediateProgram::parse_for(code, CellWidth::U16).unwrap();
+        let bf = program.compile();
+        // running the resugared code checks that `set` was expanded for 16 bit cells
+        let bf: BrainFuckProgram = bf.resugar().to_string().parse().unwrap();
 
+        let mut output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::<_, _, u16>::with_config(&mut output, stdin(), InterpreterConfig::default());
+        interpreter.execute(bf.desugar().unwrap()).unwrap();
+        assert_eq!(interpreter.tape().get_at(0), Some(1003));
+        drop(interpreter);
+        assert_eq!(output, [(1003 % 256) as u8]);
+
+        assert!(LowLevelIntermediateProgram::parse_for("a = 65536;", CellWidth::U16).is_err());
+    }
+
+    #[test]
+    fn variables_right_of_the_start() {
+        // variables are laid out to the right, so LIL doesn't need a tape that wraps around
+        let program = LowLevelIntermediateProgram::parse("one = 1; a = 5; b = 7; c = 0; while a != 0 { c += b; a -= one; } print c;").unwrap();
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Fixed(16), EdgePolicy::Error).unwrap(),
+            strict: true,
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, &b""[..], config).execute(program.compile()).unwrap();
+        assert_eq!(output, [35]);
+    }
+}
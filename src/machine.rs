@@ -0,0 +1,140 @@
+use std::io::Empty;
+use crate::cell::Cell;
+use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+use crate::interpreter::{BrainFuckInterpreter, ExecutionSummary, InterpreterConfig, Progress, RuntimeError, Yield};
+use crate::sandbox::{Limit, LimitExceeded};
+
+/// Why [`Machine::run`] returned.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Status {
+    /// the program wants to read, but all input was used. Continue after
+    /// [`push_input`](Machine::push_input) or [`close_input`](Machine::close_input).
+    NeedsInput,
+    /// the program wrote output, usually a single byte
+    Output(Vec<u8>),
+    /// the program used up its fuel. Continue after [`add_fuel`](Machine::add_fuel).
+    OutOfFuel,
+    /// the program ran to the end
+    Halted(ExecutionSummary),
+}
+
+/// Runs a program without blocking on input or output. Instead, [`run`](Self::run) returns
+/// whenever the program needs input or writes output, and the program continues where it left
+/// off when `run` is called again.
+///
+/// The machine runs with the interpreter's configuration, except that input is always raw.
+pub struct Machine<C: Cell = u8> {
+    interpreter: BrainFuckInterpreter<Vec<u8>, Empty, C>,
+    // until the first run
+    program: Option<DesugaredBrainFuckProgram>,
+}
+
+impl<C: Cell> Machine<C> {
+    pub fn new(program: DesugaredBrainFuckProgram, config: InterpreterConfig) -> Self {
+        let mut interpreter = BrainFuckInterpreter::with_config(Vec::new(), std::io::empty(), config);
+        interpreter.yield_io();
+        Self {
+            interpreter,
+            program: Some(program),
+        }
+    }
+
+    /// Runs the program until it needs input, writes output, runs out of fuel or ends. Errors,
+    /// including limits other than fuel, stop the program like they do for
+    /// [`BrainFuckInterpreter::execute`].
+    pub fn run(&mut self) -> Result<Status, RuntimeError> {
+        let progress = match self.program.take() {
+            Some(program) => self.interpreter.start(program),
+            None => self.interpreter.proceed(),
+        };
+
+        match progress {
+            Ok(Progress::Yielded(Yield::Input)) => Ok(Status::NeedsInput),
+            Ok(Progress::Yielded(Yield::Output)) => Ok(Status::Output(self.interpreter.take_output())),
+            Ok(Progress::Finished(summary)) => {
+                // output by the very last instruction comes before halting
+                let output = self.interpreter.take_output();
+                Ok(if output.is_empty() { Status::Halted(summary) } else { Status::Output(output) })
+            }
+            Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, .. })) => Ok(Status::OutOfFuel),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.interpreter.push_input(bytes);
+    }
+
+    /// Marks the end of the input. Once the pushed input is used up, reading follows the
+    /// [`EofPolicy`](crate::interpreter::EofPolicy) instead of returning [`Status::NeedsInput`].
+    pub fn close_input(&mut self) {
+        self.interpreter.close_input();
+    }
+
+    pub fn add_fuel(&mut self, fuel: u64) {
+        self.interpreter.add_fuel(fuel);
+    }
+
+    /// The interpreter running the program, to look at its tape or take a snapshot.
+    pub fn interpreter(&self) -> &BrainFuckInterpreter<Vec<u8>, Empty, C> {
+        &self.interpreter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+    use crate::interpreter::{EofPolicy, InterpreterConfig, RuntimeError};
+    use crate::machine::{Machine, Status};
+    use crate::sandbox::Limits;
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    #[test]
+    fn echo() {
+        let mut machine = Machine::<u8>::new(program(",[.,]"), InterpreterConfig::default());
+        assert_eq!(machine.run().unwrap(), Status::NeedsInput);
+        assert_eq!(machine.run().unwrap(), Status::NeedsInput);
+
+        machine.push_input(b"hi");
+        assert_eq!(machine.run().unwrap(), Status::Output(b"h".to_vec()));
+        assert_eq!(machine.run().unwrap(), Status::Output(b"i".to_vec()));
+        assert_eq!(machine.run().unwrap(), Status::NeedsInput);
+
+        machine.close_input();
+        let Status::Halted(summary) = machine.run().unwrap() else {
+            panic!("should halt at the end of the input");
+        };
+        assert_eq!(summary.instructions, 1 + 1 + 2 * 3);
+        assert!(matches!(machine.run().unwrap(), Status::Halted(_)));
+    }
+
+    #[test]
+    fn output_at_the_end() {
+        let mut machine = Machine::<u16>::new(program("+++.>++."), InterpreterConfig::default());
+        assert_eq!(machine.run().unwrap(), Status::Output(vec![3]));
+        assert_eq!(machine.run().unwrap(), Status::Output(vec![2]));
+        assert!(matches!(machine.run().unwrap(), Status::Halted(summary) if summary.ptr == 1));
+    }
+
+    #[test]
+    fn fuel_and_errors() {
+        let config = InterpreterConfig {
+            eof: EofPolicy::Error,
+            limits: Limits { fuel: Some(5), ..Default::default() },
+            ..Default::default()
+        };
+        let mut machine = Machine::<u8>::new(program("+[>+<-]+,"), config);
+        assert_eq!(machine.run().unwrap(), Status::OutOfFuel);
+        machine.add_fuel(100);
+        assert_eq!(machine.run().unwrap(), Status::NeedsInput);
+        assert_eq!(machine.interpreter().tape().get_at(1), Some(1));
+
+        machine.close_input();
+        assert!(matches!(machine.run(), Err(RuntimeError::EndOfInput)));
+    }
+}
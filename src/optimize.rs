@@ -0,0 +1,859 @@
+use std::collections::HashMap;
+use crate::cell::{Cell, CellWidth};
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, SourceEntry, SourceMap};
+use crate::interpreter::InterpreterConfig;
+use crate::machine::{Machine, Status};
+use crate::sandbox::Limits;
+use crate::tape::{EdgePolicy, Tape, TapeKind};
+use crate::source::Span;
+
+/// Runs every optimization pass, for a program that runs with the default configuration. The
+/// optimized program writes the same output and leaves the same tape behind as the original, but
+/// usually runs far fewer instructions.
+pub fn optimize(program: &DesugaredBrainFuckProgram) -> DesugaredBrainFuckProgram {
+    optimize_for(program, InterpreterConfig::default())
+}
+
+/// Like [`optimize`], for a program that runs with `config`. Passes that would change what the
+/// program does with that configuration are left out.
+pub fn optimize_for(program: &DesugaredBrainFuckProgram, config: InterpreterConfig) -> DesugaredBrainFuckProgram {
+    // see `offsets` and `multiply_add`
+    let program = match (config.tape.kind(), config.tape.edges()) {
+        (TapeKind::GrowRight(_), EdgePolicy::Wrap) => program.clone(),
+        _ if config.strict => program.clone(),
+        _ => multiply_add(&offsets(program)),
+    };
+    known_values(&scans(&program), config)
+}
+
+// rewrites every block of a program, and the source map along with it
+fn rewrite(
+    program: &DesugaredBrainFuckProgram,
+    mut f: impl FnMut(&[DesugaredBrainFuckInstruction], Option<&[SourceEntry]>) -> (Vec<DesugaredBrainFuckInstruction>, Vec<SourceEntry>),
+) -> DesugaredBrainFuckProgram {
+    let source_map = program.source_map();
+    let (instructions, entries) = f(program.as_slice(), source_map.map(|i| i.entries()));
+    let res = DesugaredBrainFuckProgram::from_instructions(instructions);
+    match source_map {
+        Some(map) => res.with_source_map(SourceMap::new(entries, map.source().map(str::to_string))),
+        None => res,
+    }
+}
+
+// the instructions of a block being rewritten, with where they came from
+#[derive(Default)]
+struct Block {
+    instructions: Vec<DesugaredBrainFuckInstruction>,
+    entries: Vec<SourceEntry>,
+}
+
+impl Block {
+    fn push(&mut self, instruction: DesugaredBrainFuckInstruction, span: Option<Span>) {
+        self.instructions.push(instruction);
+        if let Some(span) = span {
+            self.entries.push(SourceEntry { span, end: None, body: Vec::new() });
+        }
+    }
+
+    fn push_loop(&mut self, body: Block, entry: Option<&SourceEntry>) {
+        self.instructions.push(DesugaredBrainFuckInstruction::Loop(body.instructions));
+        if let Some(entry) = entry {
+            self.entries.push(SourceEntry { span: entry.span, end: entry.end, body: body.entries });
+        }
+    }
+
+    // an instruction that stays as it was, loops included
+    fn push_original(&mut self, instruction: &DesugaredBrainFuckInstruction, entry: Option<&SourceEntry>) {
+        self.instructions.push(instruction.clone());
+        self.entries.extend(entry.cloned());
+    }
+
+    fn move_by(&mut self, delta: isize, span: Option<Span>) {
+        match delta {
+            0 => {}
+            ..=-1 => self.push(DesugaredBrainFuckInstruction::Left(delta.unsigned_abs()), span),
+            _ => self.push(DesugaredBrainFuckInstruction::Right(delta as usize), span),
+        }
+    }
+
+    // replaces the last instruction, which now also covers `span`
+    fn replace_last(&mut self, instruction: DesugaredBrainFuckInstruction, span: Option<Span>) {
+        self.instructions.pop();
+        let last = self.entries.pop();
+        let span = match (last, span) {
+            (Some(last), Some(span)) => Some(Span::new(last.span.start, span.end)),
+            (_, span) => span,
+        };
+        self.push(instruction, span);
+    }
+
+    fn finish(self) -> (Vec<DesugaredBrainFuckInstruction>, Vec<SourceEntry>) {
+        (self.instructions, self.entries)
+    }
+}
+
+/// Rewrites straight-line code into instructions that change the cells at an offset from the data
+/// pointer, followed by a single move. `>>+<-<` becomes `add 1 at 2`, `sub 1 at 1`, `left 1`.
+///
+/// Moves that turn around without changing a cell in between end the run, so the pointer still
+/// reaches the same cells in the same order, and moving off the tape grows it or fails just like
+/// before. The one exception are tapes that grow to the right but wrap around at the left, where
+/// the cell wrapping leads to depends on how far the tape has grown by then. In strict mode, a
+/// change at an offset that wraps a cell around stops with the pointer where the run started
+/// instead of on that cell, so [`optimize_for`] leaves the runs alone there.
+pub fn offsets(program: &DesugaredBrainFuckProgram) -> DesugaredBrainFuckProgram {
+    rewrite(program, |block, entries| offsets_block(block, entries).finish())
+}
+
+// a straight-line run of changes to cells around the pointer
+#[derive(Default)]
+struct Run {
+    changes: Vec<(DesugaredBrainFuckInstruction, Option<Span>)>,
+    offset: isize,
+    // the direction of the previous instruction, if it was a move
+    moving: Option<bool>,
+    moves: Option<Span>,
+}
+
+impl Run {
+    fn change(&mut self, instruction: &DesugaredBrainFuckInstruction, span: Option<Span>) {
+        use DesugaredBrainFuckInstruction::*;
+
+        let offset = self.offset;
+        let instruction = match instruction {
+            _ if offset == 0 => instruction.clone(),
+            Add(n) => AddAt(offset, *n),
+            Sub(n) => SubAt(offset, *n),
+            Zero => SetAt(offset, 0, CellWidth::U8),
+            Set(v) => SetAt(offset, *v as u32, CellWidth::U8),
+            SetWide(v, width) => SetAt(offset, *v, *width),
+            _ => unreachable!("only changes to cells are part of a run"),
+        };
+        self.changes.push((instruction, span));
+        self.moving = None;
+    }
+
+    fn flush(&mut self, block: &mut Block) {
+        let run = std::mem::take(self);
+        for (instruction, span) in run.changes {
+            block.push(instruction, span);
+        }
+        match run.offset {
+            0 => {}
+            offset if offset < 0 => block.push(DesugaredBrainFuckInstruction::Left(offset.unsigned_abs()), run.moves),
+            offset => block.push(DesugaredBrainFuckInstruction::Right(offset as usize), run.moves),
+        }
+    }
+
+    fn move_by(&mut self, delta: isize, span: Option<Span>, block: &mut Block) {
+        let right = delta > 0;
+        if self.moving == Some(!right) {
+            self.flush(block);
+        }
+        self.offset += delta;
+        self.moving = Some(right);
+        self.moves = match (self.moves, span) {
+            (Some(moves), Some(span)) => Some(Span::new(moves.start, span.end)),
+            (moves, span) => moves.or(span),
+        };
+    }
+}
+
+fn offsets_block(block: &[DesugaredBrainFuckInstruction], entries: Option<&[SourceEntry]>) -> Block {
+    use DesugaredBrainFuckInstruction::*;
+
+    let mut res = Block::default();
+    let mut run = Run::default();
+    for (idx, instruction) in block.iter().enumerate() {
+        let entry = entries.and_then(|i| i.get(idx));
+        let span = entry.map(|i| i.span);
+        match instruction {
+            Left(n) => run.move_by(-(*n as isize), span, &mut res),
+            Right(n) => run.move_by(*n as isize, span, &mut res),
+            Add(_) | Sub(_) | Zero | Set(_) | SetWide(..) => run.change(instruction, span),
+            Loop(body) => {
+                run.flush(&mut res);
+                res.push_loop(offsets_block(body, entry.map(|i| &i.body[..])), entry);
+            }
+            AddAt(..) | SubAt(..) | SetAt(..) | MultiplyAdd(_) | Scan { .. } | Input | Output | Write(_) => {
+                run.flush(&mut res);
+                res.push(instruction.clone(), span);
+            }
+        }
+    }
+    run.flush(&mut res);
+    res
+}
+
+/// Replaces loops that subtract one from their cell and only add constants to cells around it,
+/// like `[->+>++<<]`, by a [`MultiplyAdd`](DesugaredBrainFuckInstruction::MultiplyAdd) that runs
+/// in constant time.
+///
+/// Loops with input or output, nested loops, or that don't return to their cell are left alone,
+/// and so are loops that move the pointer past the cells they change, which could take it off the
+/// tape where the multiply-add wouldn't. Like [`offsets`], this doesn't work for tapes that grow to
+/// the right but wrap around at the left.
+///
+/// In strict mode a multiply-add still stops where a cell would wrap around, but not at the
+/// iteration and the cell where the loop would have, so [`optimize_for`] leaves these loops alone
+/// there.
+pub fn multiply_add(program: &DesugaredBrainFuckProgram) -> DesugaredBrainFuckProgram {
+    rewrite(program, |block, entries| multiply_add_block(block, entries).finish())
+}
+
+// the offsets and factors of a loop body that only adds constants, in the order it first
+// changes them
+fn targets(body: &[DesugaredBrainFuckInstruction]) -> Option<Vec<(isize, isize)>> {
+    use DesugaredBrainFuckInstruction::*;
+
+    let mut res: Vec<(isize, isize)> = Vec::new();
+    let mut change = |offset: isize, n: isize| match res.iter_mut().find(|(i, _)| *i == offset) {
+        Some((_, factor)) => *factor += n,
+        None => res.push((offset, n)),
+    };
+
+    let mut offset = 0isize;
+    // the cells the pointer reaches
+    let (mut lowest, mut highest) = (0, 0);
+    for instruction in body {
+        let (at, n) = match instruction {
+            Left(n) => {
+                offset -= *n as isize;
+                lowest = lowest.min(offset);
+                continue;
+            }
+            Right(n) => {
+                offset += *n as isize;
+                highest = highest.max(offset);
+                continue;
+            }
+            Add(n) => (offset, *n as isize),
+            Sub(n) => (offset, -(*n as isize)),
+            AddAt(at, n) => (offset + at, *n as isize),
+            SubAt(at, n) => (offset + at, -(*n as isize)),
+            _ => return None,
+        };
+        lowest = lowest.min(at);
+        highest = highest.max(at);
+        change(at, n);
+    }
+
+    let idx = res.iter().position(|(i, _)| *i == 0)?;
+    if offset != 0 || res.remove(idx).1 != -1 {
+        return None;
+    }
+    let reached = |i: isize| res.iter().any(|(at, _)| *at == i);
+    if (lowest != 0 && !reached(lowest)) || (highest != 0 && !reached(highest)) {
+        return None;
+    }
+    Some(res)
+}
+
+fn multiply_add_block(block: &[DesugaredBrainFuckInstruction], entries: Option<&[SourceEntry]>) -> Block {
+    let mut res = Block::default();
+    for (idx, instruction) in block.iter().enumerate() {
+        let entry = entries.and_then(|i| i.get(idx));
+        match instruction {
+            DesugaredBrainFuckInstruction::Loop(body) => match targets(body) {
+                Some(targets) => {
+                    let span = entry.map(|i| Span::new(i.span.start, i.end.unwrap_or(i.span).end));
+                    res.push(DesugaredBrainFuckInstruction::MultiplyAdd(targets), span);
+                }
+                None => res.push_loop(multiply_add_block(body, entry.map(|i| &i.body[..])), entry),
+            },
+            _ => res.push(instruction.clone(), entry.map(|i| i.span)),
+        }
+    }
+    res
+}
+
+/// Replaces loops that only move the pointer, like `[>]` or `[<<]`, by a
+/// [`Scan`](DesugaredBrainFuckInstruction::Scan) that searches the tape for the zero cell they stop
+/// on, instead of running an iteration for every cell on the way.
+pub fn scans(program: &DesugaredBrainFuckProgram) -> DesugaredBrainFuckProgram {
+    rewrite(program, |block, entries| scans_block(block, entries).finish())
+}
+
+fn scans_block(block: &[DesugaredBrainFuckInstruction], entries: Option<&[SourceEntry]>) -> Block {
+    use DesugaredBrainFuckInstruction::*;
+
+    let mut res = Block::default();
+    for (idx, instruction) in block.iter().enumerate() {
+        let entry = entries.and_then(|i| i.get(idx));
+        let stride = match instruction {
+            Loop(body) => match body[..] {
+                [Right(n)] => n as isize,
+                [Left(n)] => -(n as isize),
+                _ => {
+                    res.push_loop(scans_block(body, entry.map(|i| &i.body[..])), entry);
+                    continue;
+                }
+            },
+            _ => {
+                res.push(instruction.clone(), entry.map(|i| i.span));
+                continue;
+            }
+        };
+        let span = entry.map(|i| Span::new(i.span.start, i.end.unwrap_or(i.span).end));
+        res.push(Scan { stride }, span);
+    }
+    res
+}
+
+/// Tracks the values cells are known to have, starting from a tape of zeros, and removes what
+/// can't change anything: loops, scans and multiply-adds on a cell that is known to be zero, like
+/// comment loops at the start of a program, and clears or sets of a cell to the value it already
+/// has. A clear or set directly followed by adding to or subtracting from the same cell becomes a
+/// single set.
+///
+/// Only values that are the same for every cell width are known, so going past 255 or below zero
+/// forgets the value of a cell. The program has to start on a fresh tape with the configuration in
+/// `config`. On a tape that wraps around, everything is forgotten once the cells the pass knows
+/// about are a whole tape apart, since they might be the same cell. Sets of cells at an offset
+/// that the pointer hasn't reached yet stay, because reaching them can grow the tape or stop the
+/// program.
+pub fn known_values(program: &DesugaredBrainFuckProgram, config: InterpreterConfig) -> DesugaredBrainFuckProgram {
+    let wraps = match (config.tape.kind(), config.tape.edges()) {
+        (TapeKind::Fixed(n) | TapeKind::GrowRight(n) | TapeKind::Sparse(n), EdgePolicy::Wrap) => Some(n),
+        _ => None,
+    };
+    rewrite(program, |block, entries| {
+        let mut known = Known { fresh: true, ..Known::new(wraps) };
+        known_values_block(block, entries, &mut known, config.strict).finish()
+    })
+}
+
+// what is known about the cells around the pointer, by their position relative to where the
+// pointer was when the knowledge started
+#[derive(Default)]
+struct Known {
+    ptr: isize,
+    cells: HashMap<isize, Option<u32>>,
+    // whether the cells that haven't changed are all zero
+    fresh: bool,
+    // the size of a tape that wraps around, which grow-right tapes are at least
+    wraps: Option<usize>,
+    // the lowest and highest position the knowledge is about
+    reach: (isize, isize),
+}
+
+impl Known {
+    fn new(wraps: Option<usize>) -> Self {
+        Self { wraps, ..Default::default() }
+    }
+
+    // the position of the cell at `offset`. Positions a whole wrapping tape apart can be the same
+    // cell, so reaching that far forgets everything.
+    fn position(&mut self, offset: isize) -> isize {
+        let position = self.ptr + offset;
+        let (lowest, highest) = (self.reach.0.min(position), self.reach.1.max(position));
+        if matches!(self.wraps, Some(n) if highest.abs_diff(lowest) >= n) {
+            self.cells.clear();
+            self.fresh = false;
+            self.reach = (position, position);
+        } else {
+            self.reach = (lowest, highest);
+        }
+        position
+    }
+
+    fn get(&mut self, offset: isize) -> Option<u32> {
+        let position = self.position(offset);
+        match self.cells.get(&position) {
+            Some(value) => *value,
+            None if self.fresh => Some(0),
+            None => None,
+        }
+    }
+
+    // whether the pointer reached the cell at `offset` since the knowledge started
+    fn reached(&mut self, offset: isize) -> bool {
+        let position = self.position(offset);
+        self.cells.contains_key(&position)
+    }
+
+    fn set(&mut self, offset: isize, value: Option<u32>) {
+        // larger values depend on the cell width
+        let position = self.position(offset);
+        self.cells.insert(position, value.filter(|i| *i <= u8::MAX as u32));
+    }
+
+    // after the pointer moved by an unknown amount, only the cell it ended up on is known
+    fn forget(&mut self) {
+        *self = Known::new(self.wraps);
+        self.set(0, Some(0));
+    }
+}
+
+// the narrowest cells that hold `value`. Setting it on narrower cells truncates it, like adding up
+// to it does.
+fn narrowest(value: u32) -> CellWidth {
+    [CellWidth::U8, CellWidth::U16].into_iter().find(|i| value <= i.max_value()).unwrap_or(CellWidth::U32)
+}
+
+// in strict mode, adding past 255 stops the program on 8 bit cells, so it can't become a set
+fn known_values_block(block: &[DesugaredBrainFuckInstruction], entries: Option<&[SourceEntry]>, known: &mut Known, strict: bool) -> Block {
+    use DesugaredBrainFuckInstruction::*;
+
+    let add = |value: Option<u32>, n: usize| value?.checked_add(u32::try_from(n).ok()?);
+    let sub = |value: Option<u32>, n: usize| value?.checked_sub(u32::try_from(n).ok()?);
+
+    let mut res = Block::default();
+    for (idx, instruction) in block.iter().enumerate() {
+        let entry = entries.and_then(|i| i.get(idx));
+        let span = entry.map(|i| i.span);
+        // the cell the instruction changes, and its value afterwards
+        let (offset, value) = match instruction {
+            Loop(_) | Scan { .. } | MultiplyAdd(_) if known.get(0) == Some(0) => continue,
+            Loop(body) => {
+                // the body runs any number of times, so nothing is known when it starts
+                res.push_loop(known_values_block(body, entry.map(|i| &i.body[..]), &mut Known::new(known.wraps), strict), entry);
+                known.forget();
+                continue;
+            }
+            Scan { .. } => {
+                res.push(instruction.clone(), span);
+                known.forget();
+                continue;
+            }
+            MultiplyAdd(targets) => {
+                let value = known.get(0);
+                for (offset, factor) in targets {
+                    let target = known.get(*offset).zip(value)
+                        .and_then(|(target, value)| (target as isize).checked_add(factor.checked_mul(value as isize)?))
+                        .and_then(|i| u32::try_from(i).ok());
+                    known.set(*offset, target);
+                }
+                known.set(0, Some(0));
+                res.push(instruction.clone(), span);
+                continue;
+            }
+            Left(n) | Right(n) => {
+                known.ptr += if matches!(instruction, Left(_)) { -(*n as isize) } else { *n as isize };
+                res.push(instruction.clone(), span);
+                continue;
+            }
+            Input => {
+                known.set(0, None);
+                res.push(instruction.clone(), span);
+                continue;
+            }
+            Output => {
+                res.push(instruction.clone(), span);
+                continue;
+            }
+            Write(_) => {
+                known.set(0, Some(0));
+                res.push(instruction.clone(), span);
+                continue;
+            }
+            Zero => (0, Some(0)),
+            Set(v) => (0, Some(*v as u32)),
+            SetWide(v, _) => (0, Some(*v)),
+            SetAt(offset, v, _) => (*offset, Some(*v)),
+            Add(n) => (0, add(known.get(0), *n)),
+            Sub(n) => (0, sub(known.get(0), *n)),
+            AddAt(offset, n) => (*offset, add(known.get(*offset), *n)),
+            SubAt(offset, n) => (*offset, sub(known.get(*offset), *n)),
+        };
+
+        let sets = matches!(instruction, Zero | Set(_) | SetWide(..) | SetAt(..));
+        // adding to a cell that was just set
+        let merges = match (res.instructions.last(), instruction) {
+            (Some(Zero | Set(_) | SetWide(..)), Add(_) | Sub(_)) => true,
+            (Some(SetAt(at, ..)), AddAt(offset, _) | SubAt(offset, _)) => at == offset,
+            _ => false,
+        };
+        match value {
+            Some(_) if sets && known.get(offset) == value && (offset == 0 || known.reached(offset)) => continue,
+            Some(v) if merges && (!strict || v <= u8::MAX as u32) => {
+                let set = match (offset, u8::try_from(v)) {
+                    (0, Ok(v)) => Set(v),
+                    (0, Err(_)) => SetWide(v, narrowest(v)),
+                    _ => SetAt(offset, v, narrowest(v)),
+                };
+                res.replace_last(set, span);
+            }
+            _ => res.push(instruction.clone(), span),
+        }
+        known.set(offset, value);
+    }
+    res
+}
+
+/// Runs the start of a program at compile time, up to the first time it reads input, and replaces
+/// what ran with a single write of its output, followed by setting the cells it changed and moving
+/// the pointer to where it stopped. A program that never reads, like "Hello World", becomes a
+/// write and the tape it leaves behind.
+///
+/// The evaluation runs at most `fuel` instructions, on the tape and with the cell type the program
+/// runs with later. The rest of the program continues wherever the evaluation stopped, even in the
+/// middle of a loop. If the evaluation fails, for example by moving off the tape, or reaches the
+/// caller's output or tape limit, the program is returned unchanged so that it fails when it
+/// actually runs, after writing the same output.
+pub fn partially_evaluate<C: Cell>(program: &DesugaredBrainFuckProgram, config: InterpreterConfig, fuel: u64) -> DesugaredBrainFuckProgram {
+    let config = InterpreterConfig {
+        limits: Limits {
+            fuel: Some(fuel),
+            max_output: config.limits.max_output,
+            max_tape: config.limits.max_tape,
+            deadline: None,
+        },
+        profile: false,
+        ..config
+    };
+    let mut machine = Machine::<C>::new(program.clone(), config);
+    let mut output = Vec::new();
+    loop {
+        match machine.run() {
+            Ok(Status::Output(bytes)) => output.extend(bytes),
+            Ok(Status::NeedsInput | Status::OutOfFuel | Status::Halted(_)) => break,
+            Err(_) => return program.clone(),
+        }
+    }
+
+    let interpreter = machine.interpreter();
+    let pc = interpreter.paused_at();
+    if output.is_empty() && matches!(pc, Some(pc) if pc.path() == [0]) {
+        return program.clone();
+    }
+
+    // the cells to set, and the ends of a tape that grew, which the pointer has to reach too
+    let tape = interpreter.tape();
+    let mut cells: Vec<_> = tape.nonzero_cells().into_iter().map(|(position, value)| (position, Some(value))).collect();
+    let (start, end) = tape.bounds();
+    let fresh = Tape::<C>::new(config.tape).bounds();
+    if start < fresh.0 {
+        cells.push((start, None));
+    }
+    if end > fresh.1 {
+        cells.push((end - 1, None));
+    }
+    cells.sort_by_key(|(position, _)| *position);
+
+    rewrite(program, |block, entries| {
+        // everything that replaces the evaluated part comes from the source that ran
+        let span = program.source_map().and_then(|map| {
+            let end = match pc {
+                Some(pc) => map.span(pc)?.start,
+                None => {
+                    let last = map.entries().last()?;
+                    last.end.unwrap_or(last.span).end
+                }
+            };
+            Some(Span::new(map.entries().first()?.span.start, end))
+        });
+
+        let mut res = Block::default();
+        if !output.is_empty() {
+            res.push(DesugaredBrainFuckInstruction::Write(output.clone()), span);
+        }
+        let mut ptr = 0;
+        for (position, value) in &cells {
+            res.move_by(position - ptr, span);
+            ptr = *position;
+            if let Some(value) = value {
+                res.push(DesugaredBrainFuckInstruction::SetWide(value.to_u32(), C::WIDTH), span);
+            }
+        }
+        res.move_by(tape.ptr() - ptr, span);
+
+        if let Some(pc) = pc {
+            continue_from(&mut res, block, entries, pc.path());
+        }
+        res.finish()
+    })
+}
+
+// the rest of a program from `path`. Inside a loop, that's the rest of its body, followed by the
+// whole loop again and the rest of the block around it.
+fn continue_from(res: &mut Block, block: &[DesugaredBrainFuckInstruction], entries: Option<&[SourceEntry]>, path: &[usize]) {
+    let Some((first, inner)) = path.split_first() else {
+        return;
+    };
+    if !inner.is_empty() {
+        let DesugaredBrainFuckInstruction::Loop(body) = &block[*first] else {
+            unreachable!("only loops have instructions inside them");
+        };
+        let entry = entries.and_then(|i| i.get(*first));
+        continue_from(res, body, entry.map(|i| &i.body[..]), inner);
+    }
+    for (idx, instruction) in block.iter().enumerate().skip(*first) {
+        res.push_original(instruction, entries.and_then(|i| i.get(idx)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::cell::CellWidth;
+    use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram};
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig, RuntimeError};
+    use crate::low_intermediate::LowLevelIntermediateProgram;
+    use crate::optimize::{known_values, multiply_add, offsets, optimize, optimize_for, partially_evaluate, scans};
+    use crate::sandbox::Limits;
+    use crate::strict::TrapKind;
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    // runs the original and the optimized program, which have to write the same output and
+    // leave the same tape behind. Returns how many instructions both ran.
+    fn equivalent(original: DesugaredBrainFuckProgram, optimized: DesugaredBrainFuckProgram, input: &[u8], config: InterpreterConfig) -> (u64, u64) {
+        let run = |program: DesugaredBrainFuckProgram| {
+            let mut output = Vec::new();
+            let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, input, config);
+            let res = interpreter.execute(program).map(|i| (i.instructions, i.ptr)).map_err(|e| e.to_string());
+            let tape = (interpreter.tape().bounds(), interpreter.tape().nonzero_cells());
+            drop(interpreter);
+            (res, tape, output)
+        };
+
+        let listing = optimized.structured().to_string();
+        let (original, original_tape, original_output) = run(original);
+        let (optimized, optimized_tape, optimized_output) = run(optimized);
+        assert_eq!(original_output, optimized_output, "{listing}");
+        assert_eq!(original_tape, optimized_tape, "{listing}");
+        match (original, optimized) {
+            (Ok((a, ptr)), Ok((b, optimized_ptr))) => {
+                assert_eq!(ptr, optimized_ptr);
+                (a, b)
+            }
+            (Err(_), Err(_)) => (0, 0),
+            (a, b) => panic!("{a:?} != {b:?}"),
+        }
+    }
+
+    #[test]
+    fn rewrite() {
+        use DesugaredBrainFuckInstruction::*;
+
+        let optimized = offsets(&program(">>+<-<[->>+<<]>>>[-]<"));
+        assert_eq!(optimized.as_slice(), [
+            AddAt(2, 1),
+            SubAt(1, 1),
+            Loop(vec![Sub(1), AddAt(2, 1)]),
+            SetAt(3, 0, crate::cell::CellWidth::U8),
+            Right(2),
+        ]);
+        let spans: Vec<_> = optimized.source_map().unwrap().entries().iter().map(|i| (i.span.start.column, i.span.end.column)).collect();
+        assert_eq!(spans, [(3, 4), (5, 6), (7, 8), (18, 21), (15, 22)]);
+        assert_eq!(optimized.resugar().to_string(), ">>+<<>-<[->>+<<]>>>[-]<<<>>\n");
+
+        // turning around without changing a cell ends the run
+        assert_eq!(offsets(&program("+<<>>>+")).as_slice(), [Add(1), Left(2), AddAt(3, 1), Right(3)]);
+    }
+
+    #[test]
+    fn same_output() {
+        use DesugaredBrainFuckInstruction::*;
+
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let (original, optimized) = equivalent(program(hello), offsets(&program(hello)), b"", InterpreterConfig::default());
+        assert!(optimized < original);
+
+        let code = "one = 1;\na = 5;\nb = 0;\nc = 7;\nwhile a != 0 {\n    b += a;\n    c -= one;\n    a -= one;\n}\nprint b;\nprint c;";
+        let lil = LowLevelIntermediateProgram::parse(code).unwrap().compile();
+        let (original, optimized) = equivalent(lil.clone(), offsets(&lil), b"", InterpreterConfig::default());
+        assert!(optimized * 3 < original * 2, "{optimized} out of {original}");
+
+        // moving off the tape still wraps, grows and fails in the same places
+        let codes = ["+>>+++++>-<<<<+>>>>>>>+<<<.", ",[>>>+<<<<<+>>-]>+<<<<<<+.", "<+>>>>>+<<<<<<<<-"];
+        for kind in [TapeKind::Fixed(4), TapeKind::GrowRight(2), TapeKind::Infinite] {
+            for edges in [EdgePolicy::Wrap, EdgePolicy::Error] {
+                if kind == TapeKind::GrowRight(2) && edges == EdgePolicy::Wrap {
+                    continue;
+                }
+                let config = InterpreterConfig { tape: TapeConfig::new(kind, edges).unwrap(), ..Default::default() };
+                for code in codes {
+                    equivalent(program(code), offsets(&program(code)), b"\x03", config);
+                }
+            }
+        }
+
+        // except on tapes that grow to the right but wrap at the left, which are left alone
+        let config = InterpreterConfig { tape: TapeConfig::new(TapeKind::GrowRight(2), EdgePolicy::Wrap).unwrap(), ..Default::default() };
+        for code in codes {
+            let optimized = optimize_for(&program(code), config);
+            assert!(!optimized.as_slice().iter().any(|i| matches!(i, AddAt(..) | SubAt(..) | SetAt(..))), "{}", optimized.structured());
+            equivalent(program(code), optimized, b"\x03", config);
+        }
+    }
+
+    #[test]
+    fn multiply() {
+        use DesugaredBrainFuckInstruction::*;
+
+        let optimized = multiply_add(&program("+++[->+>++<<]>[-<->]"));
+        assert_eq!(optimized.as_slice(), [Add(3), MultiplyAdd(vec![(1, 1), (2, 2)]), Right(1), MultiplyAdd(vec![(-1, -1)])]);
+        assert_eq!(optimized.resugar().to_string(), "+++[->+<>>++<<]>[-<->]\n");
+        let spans: Vec<_> = optimized.source_map().unwrap().entries().iter().map(|i| (i.span.start.column, i.span.end.column)).collect();
+        assert_eq!(spans, [(1, 4), (4, 14), (14, 15), (15, 21)]);
+
+        // the copy loops of a LIL program, also after rewriting them to offsets
+        let code = "one = 1;\na = 5;\nb = 0;\nc = 7;\nwhile a != 0 {\n    b += a;\n    c -= one;\n    a -= one;\n}\nprint b;\nprint c;";
+        let lil = LowLevelIntermediateProgram::parse(code).unwrap().compile();
+        let (original, optimized) = equivalent(lil.clone(), optimize(&lil), b"", InterpreterConfig::default());
+        assert!(optimized * 4 < original, "{optimized} out of {original}");
+
+        let code = "++++++[>++++++++<-]>[->+>+++<<]>>[-<<+>>]<<<,[->+<]>.";
+        for kind in [TapeKind::Fixed(4), TapeKind::Infinite] {
+            let config = InterpreterConfig { tape: TapeConfig::new(kind, EdgePolicy::Error).unwrap(), ..Default::default() };
+            equivalent(program(code), optimize(&program(code)), b"\x07", config);
+        }
+    }
+
+    #[test]
+    fn multiply_bails() {
+        for code in [
+            // output, input, a nested loop
+            "+[->+<.]",
+            "+[->,<]",
+            "+[->[-]<]",
+            // doesn't return to its cell, or doesn't subtract exactly one from it
+            "+[->+<<]",
+            "+[-->+<]",
+            "+[>+<]",
+            // passes cells it doesn't change
+            "+[->>><+<<]",
+        ] {
+            let optimized = multiply_add(&program(code));
+            assert!(matches!(optimized.as_slice()[1], DesugaredBrainFuckInstruction::Loop(_)), "{code}");
+        }
+    }
+
+    #[test]
+    fn strict_overflow() {
+        let code = "+++++[->+++++++++++++++++++++++++++++++++++++++++++++++++++++++<]";
+        let config = InterpreterConfig { strict: true, ..Default::default() };
+        for program in [program(code), multiply_add(&program(code))] {
+            let res = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), &b""[..], config).execute(program);
+            assert!(matches!(res, Err(RuntimeError::Trap(trap)) if matches!(trap.kind, TrapKind::CellOverflow { .. })));
+        }
+
+        // optimized for strict mode, the loop stops at the first addition that overflows, on the
+        // cell it overflows
+        let code = format!("+++[->{}<]", "+".repeat(100));
+        let optimized = optimize_for(&program(&code), config);
+        assert!(!optimized.as_slice().iter().any(|i| matches!(i, DesugaredBrainFuckInstruction::MultiplyAdd(_))));
+        for program in [program(&code), optimized] {
+            let res = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), &b""[..], config).execute(program);
+            let Err(RuntimeError::Trap(trap)) = res else {
+                panic!("{res:?}");
+            };
+            assert_eq!((trap.kind, trap.ptr), (TrapKind::CellOverflow { value: 200, added: 100, max: 255 }, 1));
+        }
+    }
+
+    #[test]
+    fn known() {
+        use DesugaredBrainFuckInstruction::*;
+
+        let code = "[->+<]+++[-]++>[-]<[->+<]>>[<]";
+        let optimized = known_values(&scans(&program(code)), InterpreterConfig::default());
+        assert_eq!(optimized.as_slice(), [
+            Add(3),
+            Set(2),
+            Right(1),
+            Left(1),
+            Loop(vec![Sub(1), Right(1), Add(1), Left(1)]),
+            Right(2),
+            Scan { stride: -1 },
+        ]);
+        let spans: Vec<_> = optimized.source_map().unwrap().entries().iter().map(|i| (i.span.start.column, i.span.end.column)).collect();
+        assert_eq!(spans, [(7, 10), (10, 15), (15, 16), (19, 20), (20, 21), (26, 28), (28, 31)]);
+        equivalent(program(code), optimized, b"", InterpreterConfig::default());
+
+        // only values that every cell width agrees on
+        let code = "-[->+<]".to_string() + &"+".repeat(256) + "[-].";
+        assert_eq!(known_values(&program(&code), InterpreterConfig::default()).as_slice().len(), program(&code).as_slice().len());
+
+        // but setting a larger value, which wider cells keep, still merges with what it adds
+        let code = ",[-]".to_string() + &"+".repeat(256) + "[.[-]]";
+        let optimized = known_values(&program(&code), InterpreterConfig::default());
+        assert_eq!(optimized.as_slice()[1], SetWide(256, CellWidth::U16));
+        let run = |program: DesugaredBrainFuckProgram| {
+            let mut output = Vec::new();
+            BrainFuckInterpreter::<_, _, u16>::with_config(&mut output, &b"x"[..], InterpreterConfig::default()).execute(program).unwrap();
+            output
+        };
+        assert_eq!(run(program(&code)), run(optimized.clone()));
+        equivalent(program(&code), optimized, b"x", InterpreterConfig::default());
+        // unless adding past 255 stops the program
+        let strict = InterpreterConfig { strict: true, ..Default::default() };
+        assert_eq!(known_values(&program(&code), strict).as_slice().len(), program(&code).as_slice().len());
+
+        // moving a whole tape around ends up on a cell that changed
+        let config = InterpreterConfig { tape: TapeConfig::new(TapeKind::Fixed(4), EdgePolicy::Wrap).unwrap(), ..Default::default() };
+        let code = "+>>>>[.-]";
+        equivalent(program(code), optimize_for(&program(code), config), b"", config);
+        assert_eq!(optimize_for(&program(code), config).as_slice().len(), program(code).as_slice().len());
+
+        // clearing a cell that wasn't reached yet still moves off the tape, or grows it
+        for edges in [EdgePolicy::Error, EdgePolicy::Grow] {
+            let config = InterpreterConfig { tape: TapeConfig::new(TapeKind::Fixed(4), edges).unwrap(), ..Default::default() };
+            for config in [config, InterpreterConfig { strict: true, ..config }] {
+                for code in [">>>>>>[-]<<<<<<+.", "<[-]>+."] {
+                    equivalent(program(code), known_values(&offsets(&program(code)), config), b"", config);
+                }
+            }
+        }
+
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let (original, optimized) = equivalent(program(hello), optimize(&program(hello)), b"", InterpreterConfig::default());
+        assert!(optimized * 2 < original, "{optimized} out of {original}");
+    }
+
+    #[test]
+    fn partial_evaluation() {
+        use DesugaredBrainFuckInstruction::*;
+
+        let hello = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+        let config = InterpreterConfig::default();
+        let evaluated = partially_evaluate::<u8>(&program(hello), config, 10_000);
+        assert_eq!(evaluated.as_slice()[0], Write(b"Hello World!\n".to_vec()));
+        assert!(evaluated.as_slice().iter().all(|i| !matches!(i, Loop(_) | Output)));
+        let (original, evaluated) = equivalent(program(hello), evaluated, b"", config);
+        assert!(evaluated * 10 < original, "{evaluated} out of {original}");
+
+        let evaluated = partially_evaluate::<u8>(&program("+++.>,."), config, 100);
+        assert_eq!(evaluated.resugar().to_string(), "[-]+++.[-][-]+++>,.\n");
+        let spans: Vec<_> = evaluated.source_map().unwrap().entries().iter().map(|i| (i.span.start.column, i.span.end.column)).collect();
+        assert_eq!(spans, [(1, 6), (1, 6), (1, 6), (6, 7), (7, 8)]);
+
+        // running out of fuel in the middle of a loop, and a tape that grew
+        let code = "++++++++[>++++++++<-]>+.>>>[-]<<<[>+<-],[.,]";
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::GrowRight(1), EdgePolicy::Error).unwrap(),
+            ..Default::default()
+        };
+        for fuel in [0, 5, 20, 31, 1000] {
+            equivalent(program(code), partially_evaluate::<u8>(&program(code), config, fuel), b"echo", config);
+        }
+
+        // failing at runtime
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Fixed(2), EdgePolicy::Error).unwrap(),
+            ..Default::default()
+        };
+        let evaluated = partially_evaluate::<u8>(&program("+.>>."), config, 100);
+        assert_eq!(evaluated.as_slice(), program("+.>>.").as_slice());
+
+        // reaching a limit writes the same output before it stops
+        let code = "++++++++[>++++++++<-]>+.+.";
+        for limits in [Limits { max_output: Some(1), ..Default::default() }, Limits { max_tape: Some(1), ..Default::default() }] {
+            let config = InterpreterConfig { limits, ..Default::default() };
+            let evaluated = partially_evaluate::<u8>(&program(code), config, 1000);
+            assert_eq!(evaluated.as_slice(), program(code).as_slice());
+        }
+        let config = InterpreterConfig { limits: Limits { max_output: Some(2), ..Default::default() }, ..Default::default() };
+        let evaluated = partially_evaluate::<u8>(&program(code), config, 1000);
+        assert_eq!(evaluated.as_slice()[0], Write(b"AB".to_vec()));
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, &b""[..], config).execute(evaluated).unwrap();
+        assert_eq!(output, b"AB");
+    }
+}
@@ -1,92 +1,135 @@
-use core::iter::Iterator;
 use std::fmt::{Display, Formatter};
-use std::str::{Chars, FromStr};
-use itertools::{Itertools, MultiPeek};
+use std::str::FromStr;
+use crate::source::{Snippet, SourcePosition, Span};
 
-#[derive(Clone)]
-pub struct Parser<'a> {
-    orig: &'a str,
-    stream: MultiPeek<Chars<'a>>,
-    lines: usize,
+/// A single syntax error: what the parser wanted at some point and what it found there instead.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    pub span: Span,
+    pub expected: Vec<String>,
+    pub found: String,
+    snippet: Box<Snippet>,
 }
 
-impl Display for Parser<'_> {
+impl Display for Diagnostic {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        if let Some(line) = self.orig.lines().skip(self.lines).next() {
-            writeln!(f)?;
-            writeln!(f, "{}", line)?;
-
-            let len = line.len();
-            if let Some(next_newline) = self.stream.clone().position(|i| i == '\n') {
-                let from_prev = len - next_newline;
-                write!(f, "{:level$}^", "", level = from_prev)?;
-            }
+        match self.expected.as_slice() {
+            [] => write!(f, "unexpected {}", self.found)?,
+            [expected] => write!(f, "expected {expected}, found {}", self.found)?,
+            expected => write!(f, "expected one of {}, found {}", expected.join(", "), self.found)?,
+        }
+        write!(f, " at {}\n{}", self.span.start, self.snippet)
+    }
+}
 
-            Ok(())
-        } else {
-            write!(f, "<unknown file location>")
+/// All syntax errors found in a program, in source order.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ParseError {
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl ParseError {
+    pub fn new(diagnostics: Vec<Diagnostic>) -> Self {
+        Self {
+            diagnostics,
+        }
+    }
+
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        &self.diagnostics
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (idx, i) in self.diagnostics.iter().enumerate() {
+            if idx != 0 {
+                writeln!(f)?;
+                writeln!(f)?;
+            }
+            write!(f, "{i}")?;
         }
+        Ok(())
     }
 }
 
+impl std::error::Error for ParseError {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum CommentKind {
+    /// `// ...` up to the end of the line
+    Line,
+    /// `/* ... */`, which may contain other block comments
+    Block,
+}
+
+/// A comment in the source, kept around so tools can reproduce it in their output.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Comment {
+    pub kind: CommentKind,
+    pub span: Span,
+    /// the text of the comment without the delimiters
+    pub text: String,
+}
+
+#[derive(Clone)]
+pub struct Parser<'a> {
+    orig: &'a str,
+    position: SourcePosition,
+    comments: Vec<Comment>,
+    // errors in trivia, which have nowhere else to go since skipping whitespace can't fail
+    trivia_errors: Vec<Diagnostic>,
+}
+
 impl<'a> Parser<'a> {
     pub fn new(i: &'a str) -> Self {
         Self {
             orig: i,
-            stream: i.chars().multipeek(),
-            lines: 0,
+            position: SourcePosition::start(),
+            comments: Vec::new(),
+            trivia_errors: Vec::new(),
         }
     }
 
+    /// The comments and the errors in them seen so far.
+    pub fn finish(self) -> (Vec<Comment>, Vec<Diagnostic>) {
+        (self.comments, self.trivia_errors)
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.orig[self.position.offset..]
+    }
+
     fn next(&mut self) -> Option<char> {
-        let c = self.stream.next();
+        let c = self.rest().chars().next();
         if let Some(i) = c {
-            if i == '\n' {
-                self.lines += 1;
-            }
+            self.position = self.position.advance(i);
         }
         c
     }
 
-    pub fn is_empty(&mut self) -> bool {
-        self.stream.peek().is_none()
+    pub fn position(&self) -> SourcePosition {
+        self.position
+    }
+
+    pub fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
     }
 
     pub fn accept(&mut self, c: char) -> Option<()> {
-        self.stream.reset_peek();
-        let next = self.stream.peek();
-        if next == Some(&c) {
-            let _ = self.next();
-            // println!("accept: {c}");
-            Some(())
-        } else {
-            self.stream.reset_peek();
-            None
-        }
+        self.accept_with(|i| i == c).map(|_| ())
     }
 
     pub fn accept_with(&mut self, c: impl Fn(char) -> bool) -> Option<char> {
-        self.stream.reset_peek();
-        if let Some(&i) = self.stream.peek() {
-            if c(i) {
-                let _ = self.next();
-                Some(i)
-            } else {
-                self.stream.reset_peek();
-                None
-            }
-        } else {
-            self.stream.reset_peek();
-            None
+        match self.peek() {
+            Some(i) if c(i) => self.next(),
+            _ => None,
         }
     }
 
     pub fn accept_str(&mut self, s: &str) -> Option<()> {
-        for i in s.chars() {
-            if self.stream.peek() != Some(&i) {
-                self.stream.reset_peek();
-                return None;
-            }
+        if !self.rest().starts_with(s) {
+            return None;
         }
 
         for _ in s.chars() {
@@ -96,46 +139,158 @@ impl<'a> Parser<'a> {
         Some(())
     }
 
+    /// Like `accept_str`, but doesn't match the start of a longer identifier.
+    pub fn accept_keyword(&mut self, s: &str) -> Option<()> {
+        let rest = self.rest();
+        if !rest.starts_with(s) || rest[s.len()..].starts_with(|i: char| i.is_alphanumeric()) {
+            return None;
+        }
+
+        self.accept_str(s)
+    }
+
+    /// Skips whitespace and comments.
     pub fn whitespace(&mut self) -> bool {
         let mut res = false;
 
-        while self.accept_with(|i| i.is_whitespace()).is_some() {
-            res = true;
+        loop {
+            if self.accept_with(|i| i.is_whitespace()).is_some() {
+                res = true;
+            } else if self.rest().starts_with("//") {
+                self.line_comment();
+                res = true;
+            } else if self.rest().starts_with("/*") {
+                self.block_comment();
+                res = true;
+            } else {
+                return res;
+            }
         }
+    }
+
+    fn line_comment(&mut self) {
+        let start = self.position;
+        self.accept_str("//");
+        let text_start = self.position.offset;
+        while self.accept_with(|i| i != '\n').is_some() {}
 
-        res
+        self.comments.push(Comment {
+            kind: CommentKind::Line,
+            span: Span::new(start, self.position),
+            text: self.orig[text_start..self.position.offset].to_string(),
+        });
     }
 
-    pub fn parse_num<T: FromStr>(&mut self) -> Option<T> {
-        let mut res = String::new();
+    fn block_comment(&mut self) {
+        let start = self.position;
+        self.accept_str("/*");
+        let text_start = self.position.offset;
 
-        while let Some(i) = self.accept_with(|i| i.is_ascii_digit()) {
-            res.push(i)
+        let mut depth = 1;
+        let mut text_end = self.orig.len();
+        while depth > 0 {
+            if self.accept_str("/*").is_some() {
+                depth += 1;
+            } else if self.rest().starts_with("*/") {
+                depth -= 1;
+                text_end = self.position.offset;
+                self.accept_str("*/");
+            } else if self.next().is_none() {
+                let mut error = self.error_at(start, "/*", &["'*/'"]);
+                error.found = "end of input".to_string();
+                self.trivia_errors.push(error);
+                break;
+            }
         }
 
-        if res.is_empty() {
-            None
-        } else {
-            // println!("num: {res}");
-            Some(res.parse().ok()?)
-        }
+        self.comments.push(Comment {
+            kind: CommentKind::Block,
+            span: Span::new(start, self.position),
+            text: self.orig[text_start..text_end].to_string(),
+        });
     }
 
+    pub fn parse_num<T: FromStr + PartialOrd>(&mut self, max: T) -> Option<T> {
+        let digits = self.rest().chars().take_while(|i| i.is_ascii_digit()).count();
+        if digits == 0 {
+            return None;
+        }
+
+        // leave out of range numbers in the input, so the error points at them
+        let res = self.rest()[..digits].parse().ok().filter(|i| *i <= max)?;
+        for _ in 0..digits {
+            self.next();
+        }
+        Some(res)
+    }
 
     pub fn parse_ident(&mut self) -> Option<String> {
-        let mut res = String::new();
+        if !self.rest().starts_with(|i: char| i.is_alphabetic()) {
+            return None;
+        }
 
+        let mut res = String::new();
         while let Some(i) = self.accept_with(|i| i.is_alphanumeric()) {
             res.push(i)
         }
 
-        if res.len() == 0 {
-            return None
+        Some(res)
+    }
+
+    // the text of the token at the current position, without consuming it
+    fn peek_token(&self) -> &'a str {
+        let rest = self.rest();
+        let Some(first) = rest.chars().next() else {
+            return "";
+        };
+
+        if first.is_alphanumeric() {
+            let len = rest.find(|i: char| !i.is_alphanumeric()).unwrap_or(rest.len());
+            &rest[..len]
+        } else if ["+=", "-=", "!="].iter().any(|i| rest.starts_with(i)) {
+            &rest[..2]
+        } else {
+            &rest[..first.len_utf8()]
         }
-        if res.chars().next().unwrap().is_ascii_digit() {
-            return None
+    }
+
+    /// Builds a diagnostic for the next token, which wasn't any of `expected`.
+    pub fn error(&mut self, expected: &[&str]) -> Diagnostic {
+        self.whitespace();
+        self.error_at(self.position, self.peek_token(), expected)
+    }
+
+    fn error_at(&self, start: SourcePosition, token: &str, expected: &[&str]) -> Diagnostic {
+        let end = token.chars().fold(start, |pos, c| pos.advance(c));
+
+        Diagnostic {
+            span: Span::new(start, end),
+            expected: expected.iter().map(|i| i.to_string()).collect(),
+            found: if token.is_empty() {
+                "end of input".to_string()
+            } else {
+                format!("'{token}'")
+            },
+            snippet: Box::new(Snippet::new(self.orig, start, token.chars().count())),
         }
+    }
 
-        Some(res)
+    /// Skips to just after the next `;`, or to the next `}` without consuming it, so parsing can
+    /// continue with the next statement after an error.
+    pub fn recover(&mut self) {
+        loop {
+            // comments may contain either character
+            self.whitespace();
+            match self.peek() {
+                None | Some('}') => return,
+                Some(';') => {
+                    self.next();
+                    return;
+                }
+                Some(_) => {
+                    self.next();
+                }
+            }
+        }
     }
 }
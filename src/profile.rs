@@ -0,0 +1,267 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, ProgramCounter, SourceEntry};
+use crate::source::Span;
+
+// how often the instructions of a block ran, in the same shape as the block
+#[derive(Clone, Default)]
+pub(crate) struct Counter {
+    pub executed: u64,
+    pub iterations: u64,
+    pub body: Vec<Counter>,
+}
+
+impl Counter {
+    pub fn for_block(block: &[DesugaredBrainFuckInstruction]) -> Vec<Counter> {
+        block.iter()
+            .map(|i| Counter {
+                body: match i {
+                    DesugaredBrainFuckInstruction::Loop(body) => Self::for_block(body),
+                    _ => Vec::new(),
+                },
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+/// How often a single instruction ran.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct InstructionProfile {
+    pub pc: ProgramCounter,
+    pub name: String,
+    /// the source the instruction came from, for loops including their body
+    pub span: Option<Span>,
+    /// how often the instruction ran, or for loops how often they were started
+    pub executed: u64,
+    /// for loops, how often the body ran
+    pub iterations: u64,
+}
+
+impl InstructionProfile {
+    /// The instructions this one accounts for, counted like
+    /// [`ExecutionSummary::instructions`](crate::interpreter::ExecutionSummary::instructions).
+    pub fn cost(&self) -> u64 {
+        self.executed + self.iterations
+    }
+}
+
+/// Instructions that came from the same piece of source, like a LIL statement.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SourceProfile {
+    pub span: Span,
+    pub instructions: u64,
+}
+
+/// How often every instruction of a program ran, collected by an interpreter with
+/// [`InterpreterConfig::profile`](crate::interpreter::InterpreterConfig::profile) set.
+#[derive(Clone, Debug)]
+pub struct Profile {
+    instructions: Vec<InstructionProfile>,
+    source: Option<String>,
+}
+
+impl Profile {
+    pub(crate) fn new(program: &DesugaredBrainFuckProgram, counters: &[Counter]) -> Self {
+        let mut res = Self {
+            instructions: Vec::new(),
+            source: program.source_map().and_then(|i| i.source()).map(str::to_string),
+        };
+        let entries = program.source_map().map(|i| i.entries());
+        res.add_block(program.as_slice(), counters, entries, &mut Vec::new());
+        res
+    }
+
+    fn add_block(&mut self, block: &[DesugaredBrainFuckInstruction], counters: &[Counter], entries: Option<&[SourceEntry]>, path: &mut Vec<usize>) {
+        for (idx, (instruction, counter)) in block.iter().zip(counters).enumerate() {
+            path.push(idx);
+            let entry = entries.and_then(|i| i.get(idx));
+            self.instructions.push(InstructionProfile {
+                pc: ProgramCounter::new(path.clone()),
+                name: instruction.name(),
+                span: entry.map(|i| Span::new(i.span.start, i.end.unwrap_or(i.span).end)),
+                executed: counter.executed,
+                iterations: counter.iterations,
+            });
+            if let DesugaredBrainFuckInstruction::Loop(body) = instruction {
+                self.add_block(body, &counter.body, entry.map(|i| &i.body[..]), path);
+            }
+            path.pop();
+        }
+    }
+
+    /// Every instruction, in program order.
+    pub fn instructions(&self) -> &[InstructionProfile] {
+        &self.instructions
+    }
+
+    // the source the spans point into
+    pub(crate) fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.instructions.iter().map(|i| i.cost()).sum()
+    }
+
+    /// The loops that ran, with the most iterations first.
+    pub fn hottest_loops(&self) -> Vec<&InstructionProfile> {
+        let mut res: Vec<_> = self.instructions.iter()
+            .filter(|i| i.name == "loop" && i.executed > 0)
+            .collect();
+        res.sort_by(|a, b| b.iterations.cmp(&a.iterations).then_with(|| a.pc.path().cmp(b.pc.path())));
+        res
+    }
+
+    /// The instructions that ran grouped by the source they came from, the most first. For
+    /// programs compiled from LIL, these are the statements.
+    pub fn by_source(&self) -> Vec<SourceProfile> {
+        let mut counts = HashMap::new();
+        for i in &self.instructions {
+            if let Some(span) = i.span {
+                *counts.entry(span).or_insert(0) += i.cost();
+            }
+        }
+
+        let mut res: Vec<_> = counts.into_iter()
+            .filter(|(_, instructions)| *instructions > 0)
+            .map(|(span, instructions)| SourceProfile { span, instructions })
+            .collect();
+        res.sort_by(|a, b| b.instructions.cmp(&a.instructions).then_with(|| a.span.start.cmp(&b.span.start)));
+        res
+    }
+
+    /// A report of the hottest `n` loops and pieces of source.
+    pub fn report(&self, n: usize) -> Report<'_> {
+        Report {
+            profile: self,
+            n,
+        }
+    }
+
+    // the first line of the source in `span`, shortened if it's long
+    fn excerpt(&self, span: Span) -> Option<String> {
+        let text = self.source.as_ref()?.get(span.start.offset..span.end.offset)?;
+        let line = text.lines().next().unwrap_or("").trim();
+        Some(match line.char_indices().nth(40) {
+            Some((end, _)) => format!("{}...", &line[..end]),
+            None => line.to_string(),
+        })
+    }
+
+    fn fmt_location(&self, f: &mut Formatter<'_>, span: Option<Span>, instruction: Option<&InstructionProfile>) -> std::fmt::Result {
+        match (span, instruction) {
+            (Some(span), _) => {
+                write!(f, "{}", span.start)?;
+                if let Some(excerpt) = self.excerpt(span) {
+                    write!(f, "  {excerpt}")?;
+                }
+                Ok(())
+            }
+            (None, Some(i)) => write!(f, "instruction {} ({})", i.pc, i.name),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+pub struct Report<'a> {
+    profile: &'a Profile,
+    n: usize,
+}
+
+impl Display for Report<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let profile = self.profile;
+        writeln!(f, "{} instructions executed", profile.total())?;
+
+        let loops = profile.hottest_loops();
+        if !loops.is_empty() {
+            writeln!(f)?;
+            writeln!(f, "hottest loops:")?;
+            writeln!(f, "{:>12} {:>12}  location", "iterations", "started")?;
+            for i in loops.iter().take(self.n) {
+                write!(f, "{:>12} {:>12}  ", i.iterations, i.executed)?;
+                profile.fmt_location(f, i.span, Some(i))?;
+                writeln!(f)?;
+            }
+        }
+
+        let sources = profile.by_source();
+        if sources.is_empty() {
+            // without source positions, the instructions themselves are the best there is
+            let mut instructions: Vec<_> = profile.instructions.iter().filter(|i| i.cost() > 0).collect();
+            instructions.sort_by_key(|i| std::cmp::Reverse(i.cost()));
+            writeln!(f)?;
+            writeln!(f, "hottest instructions:")?;
+            writeln!(f, "{:>12}  location", "executed")?;
+            for i in instructions.iter().take(self.n) {
+                write!(f, "{:>12}  ", i.cost())?;
+                profile.fmt_location(f, None, Some(i))?;
+                writeln!(f)?;
+            }
+        } else {
+            writeln!(f)?;
+            writeln!(f, "hottest source:")?;
+            writeln!(f, "{:>12}  location", "executed")?;
+            for i in sources.iter().take(self.n) {
+                write!(f, "{:>12}  ", i.instructions)?;
+                profile.fmt_location(f, Some(i.span), None)?;
+                writeln!(f)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::desugared_brainfuck::ProgramCounter;
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig};
+    use crate::low_intermediate::LowLevelIntermediateProgram;
+    use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+    use crate::profile::Profile;
+
+    fn profile(program: DesugaredBrainFuckProgram) -> Profile {
+        let config = InterpreterConfig { profile: true, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let summary = interpreter.execute(program).unwrap();
+
+        let profile = interpreter.profile().unwrap().clone();
+        assert_eq!(profile.total(), summary.instructions);
+        profile
+    }
+
+    #[test]
+    fn brainfuck() {
+        let program: BrainFuckProgram = "+++[>++++[>+<-]<-]".parse().unwrap();
+        let profile = profile(program.desugar().unwrap());
+
+        let loops = profile.hottest_loops();
+        assert_eq!(loops.len(), 2);
+        assert_eq!((&loops[0].pc, loops[0].executed, loops[0].iterations), (&ProgramCounter::new(vec![1, 2]), 3, 12));
+        assert_eq!((&loops[1].pc, loops[1].executed, loops[1].iterations), (&ProgramCounter::new(vec![1]), 1, 3));
+        assert_eq!(loops[0].span.map(|i| (i.start.column, i.end.column)), Some((10, 16)));
+
+        let add = profile.instructions().iter().find(|i| i.pc == ProgramCounter::new(vec![1, 2, 1])).unwrap();
+        assert_eq!((add.name.as_str(), add.executed), ("add 1", 12));
+    }
+
+    #[test]
+    fn lil_statements() {
+        let code = "one = 1;\na = 3;\nb = 2;\nc = 0;\nwhile a != 0 {\n    c += b;\n    a -= one;\n}";
+        let program = LowLevelIntermediateProgram::parse(code).unwrap();
+        let profile = profile(program.compile());
+
+        let report = profile.report(3).to_string();
+        let statements: Vec<_> = profile.by_source().iter()
+            .map(|i| &code[i.span.start.offset..i.span.end.offset])
+            .collect();
+        // adding two runs the loops that copy `b` twice as often as subtracting one
+        assert_eq!(statements[0], "c += b;", "{report}");
+        assert!(statements.contains(&"a -= one;"));
+        assert!(report.contains("line 6, column 5  c += b;"), "{report}");
+    }
+}
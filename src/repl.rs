@@ -0,0 +1,188 @@
+use std::io::{stdin, stdout, Stdin, Stdout, Write};
+use brainfuck_compiler::{BrainFuckInterpreter, BrainFuckProgram, DesugaredBrainFuckProgram, LilSession};
+use brainfuck_compiler::cell::Cell;
+use brainfuck_compiler::desugared_brainfuck::DesugaredBrainFuckInstruction;
+use brainfuck_compiler::interpreter::InterpreterConfig;
+
+const HELP: &str = "\
+lines are run as soon as all their loops are closed, commands start with ':'
+    :help           show this help
+    :bf             interpret lines as brainfuck
+    :lil            interpret lines as LIL
+    :tape [N]       show N cells on either side of the data pointer (default 8)
+    :code LINE      show the brainfuck a LIL line compiles to, without running it
+    :reset          clear the tape and forget all LIL variables
+    :quit           exit";
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum Mode {
+    BrainFuck,
+    Lil,
+}
+
+impl Mode {
+    fn prompt(&self) -> &'static str {
+        match self {
+            Mode::BrainFuck => "bf> ",
+            Mode::Lil => "lil> ",
+        }
+    }
+
+    // how far a piece of code is from having all its blocks closed
+    fn open_blocks(&self, code: &str) -> isize {
+        let (open, close) = match self {
+            Mode::BrainFuck => ('[', ']'),
+            Mode::Lil => ('{', '}'),
+        };
+        code.chars().fold(0, |acc, c| if c == open {
+            acc + 1
+        } else if c == close {
+            acc - 1
+        } else {
+            acc
+        })
+    }
+}
+
+pub struct Repl<C: Cell> {
+    interpreter: BrainFuckInterpreter<Stdout, Stdin, C>,
+    mode: Mode,
+    session: LilSession,
+    // where the code generated by `session` expects the data pointer to be
+    lil_ptr: isize,
+}
+
+impl<C: Cell> Repl<C> {
+    pub fn new(mode: Mode, config: InterpreterConfig) -> Self {
+        Self {
+            interpreter: BrainFuckInterpreter::with_config(stdout(), stdin(), config),
+            mode,
+            session: LilSession::for_width(C::WIDTH),
+            lil_ptr: 0,
+        }
+    }
+
+    // reads a line at a time without keeping stdin locked, since programs read from it too
+    fn read_entry(&self) -> std::io::Result<Option<String>> {
+        let mut entry = String::new();
+        let mut prompt = self.mode.prompt();
+
+        loop {
+            print!("{prompt}");
+            stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin().read_line(&mut line)? == 0 {
+                return Ok(if entry.is_empty() { None } else { Some(entry) });
+            }
+            entry.push_str(line.trim_end_matches(['\n', '\r']));
+            entry.push('\n');
+
+            if entry.trim_start().starts_with(':') || self.mode.open_blocks(&entry) <= 0 {
+                return Ok(Some(entry));
+            }
+            prompt = "... ";
+        }
+    }
+
+    pub fn run(&mut self) -> std::io::Result<()> {
+        println!("brainfuck repl, type :help for help");
+        while let Some(entry) = self.read_entry()? {
+            let entry = entry.trim();
+            if let Some(command) = entry.strip_prefix(':') {
+                if !self.command(command)? {
+                    break;
+                }
+            } else if !entry.is_empty() {
+                self.execute(entry)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // returns false when the repl should exit
+    fn command(&mut self, command: &str) -> std::io::Result<bool> {
+        let (name, arg) = command.split_once(char::is_whitespace).unwrap_or((command, ""));
+        let arg = arg.trim();
+
+        match name {
+            "help" | "h" => println!("{HELP}"),
+            "quit" | "q" => return Ok(false),
+            "bf" => self.mode = Mode::BrainFuck,
+            "lil" => self.mode = Mode::Lil,
+            "reset" => {
+                self.interpreter.reset();
+                self.session = LilSession::for_width(C::WIDTH);
+                self.lil_ptr = 0;
+            }
+            "tape" => {
+                if arg.is_empty() {
+                    self.print_tape(8);
+                } else if let Ok(radius) = arg.parse() {
+                    self.print_tape(radius);
+                } else {
+                    println!("error: expected a number of cells, not '{arg}'");
+                }
+            }
+            "code" => {
+                // compile on a copy, so nothing is allocated for a line that never runs
+                let mut session = self.session.clone();
+                match session.parse(arg) {
+                    Ok(program) => print!("{}", session.compile(&program).resugar()),
+                    Err(e) => println!("error: {e}"),
+                }
+            }
+            _ => println!("error: unknown command ':{name}', type :help for help"),
+        }
+
+        Ok(true)
+    }
+
+    fn execute(&mut self, code: &str) -> std::io::Result<()> {
+        let program = match self.mode {
+            Mode::BrainFuck => {
+                let program: BrainFuckProgram = code.parse().unwrap_or_else(|e| match e {});
+                match program.desugar() {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("error: {e}");
+                        return Ok(());
+                    }
+                }
+            }
+            Mode::Lil => {
+                let program = match self.session.parse(code) {
+                    Ok(i) => i,
+                    Err(e) => {
+                        println!("error: {e}");
+                        return Ok(());
+                    }
+                };
+
+                // brainfuck lines may have moved the pointer away from where the LIL code left it
+                let ptr = self.interpreter.ptr();
+                let mut instructions = vec![if self.lil_ptr > ptr {
+                    DesugaredBrainFuckInstruction::Right((self.lil_ptr - ptr) as usize)
+                } else {
+                    DesugaredBrainFuckInstruction::Left((ptr - self.lil_ptr) as usize)
+                }];
+                instructions.extend_from_slice(self.session.compile(&program).as_slice());
+                DesugaredBrainFuckProgram::from_instructions(instructions)
+            }
+        };
+
+        if let Err(e) = self.interpreter.execute(program) {
+            println!("error: {e}");
+        }
+        if self.mode == Mode::Lil {
+            self.lil_ptr = self.interpreter.ptr();
+        }
+
+        Ok(())
+    }
+
+    fn print_tape(&self, radius: usize) {
+        println!("{}", self.interpreter.tape().window(radius));
+    }
+}
@@ -0,0 +1,162 @@
+use std::fmt::{Display, Formatter};
+use std::time::Instant;
+use crate::desugared_brainfuck::ProgramCounter;
+
+/// Limits for running programs that can't be trusted to stop by themselves.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Limits {
+    /// how many more instructions may run, counted like
+    /// [`ExecutionSummary::instructions`](crate::interpreter::ExecutionSummary::instructions)
+    pub fuel: Option<u64>,
+    /// how many bytes the program may write in total
+    pub max_output: Option<u64>,
+    /// how many cells the tape may take up
+    pub max_tape: Option<usize>,
+    /// when to stop. The clock is only checked every so many instructions.
+    pub deadline: Option<Instant>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Limit {
+    Fuel,
+    Output,
+    Tape,
+    Deadline,
+}
+
+/// A program stopped because it reached one of its [`Limits`]. It can continue with
+/// [`BrainFuckInterpreter::resume`](crate::BrainFuckInterpreter::resume) once the limit is raised.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct LimitExceeded {
+    pub limit: Limit,
+    /// the instruction that would have run next
+    pub pc: ProgramCounter,
+    pub ptr: isize,
+    /// how many instructions ran before stopping
+    pub instructions: u64,
+}
+
+impl Display for LimitExceeded {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.limit {
+            Limit::Fuel => write!(f, "ran out of fuel")?,
+            Limit::Output => write!(f, "reached the output limit")?,
+            Limit::Tape => write!(f, "reached the tape size limit")?,
+            Limit::Deadline => write!(f, "reached the deadline")?,
+        }
+        write!(f, " after {} instructions, at instruction {} with the data pointer at {}", self.instructions, self.pc, self.ptr)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use std::time::{Duration, Instant};
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::desugared_brainfuck::{DesugaredBrainFuckProgram, ProgramCounter};
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig, RuntimeError};
+    use crate::optimize::optimize;
+    use crate::sandbox::{Limit, LimitExceeded, Limits};
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    fn limited(limits: Limits, output: &mut Vec<u8>) -> BrainFuckInterpreter<&mut Vec<u8>, std::io::Empty> {
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Infinite, EdgePolicy::Wrap).unwrap(),
+            limits,
+            ..Default::default()
+        };
+        BrainFuckInterpreter::with_config(output, empty(), config)
+    }
+
+    #[test]
+    fn resume_with_more_fuel() {
+        let code = "++++++++[>++++++++<-]>+.+.+.";
+        let mut output = Vec::new();
+        let mut interpreter = limited(Limits { fuel: Some(10), ..Default::default() }, &mut output);
+
+        let Err(RuntimeError::Limit(e)) = interpreter.execute(program(code)) else {
+            panic!("should run out of fuel");
+        };
+        // add, check, four instructions in the loop, check, and three more before the `-`
+        assert_eq!(e, LimitExceeded { limit: Limit::Fuel, pc: ProgramCounter::new(vec![1, 3]), ptr: 0, instructions: 10 });
+        assert_eq!(interpreter.paused_at(), Some(&e.pc));
+
+        let mut stops = 1;
+        loop {
+            interpreter.add_fuel(10);
+            match interpreter.resume() {
+                Ok(summary) => {
+                    assert_eq!(summary.instructions, 1 + 9 + 8 * 4 + 7);
+                    break;
+                }
+                Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Fuel, .. })) => stops += 1,
+                Err(e) => panic!("{e}"),
+            }
+        }
+        assert_eq!(stops, 4);
+        assert_eq!(interpreter.paused_at(), None);
+        drop(interpreter);
+        assert_eq!(output, b"ABC");
+    }
+
+    #[test]
+    fn output_limit() {
+        let mut output = Vec::new();
+        let mut interpreter = limited(Limits { max_output: Some(2), ..Default::default() }, &mut output);
+        let res = interpreter.execute(program("+.+.+.+."));
+        assert!(matches!(res, Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Output, ptr: 0, .. }))));
+
+        interpreter.limits_mut().max_output = None;
+        interpreter.resume().unwrap();
+        drop(interpreter);
+        assert_eq!(output, [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn tape_limit() {
+        let mut output = Vec::new();
+        let mut interpreter = limited(Limits { max_tape: Some(100), ..Default::default() }, &mut output);
+        let res = interpreter.execute(program("+[>+]"));
+        let Err(RuntimeError::Limit(e)) = res else {
+            panic!("should stop at the tape limit");
+        };
+        assert_eq!((e.limit, e.ptr, e.pc.path()), (Limit::Tape, 99, &[1, 0][..]));
+        assert_eq!(interpreter.tape().bounds(), (0, 100));
+    }
+
+    #[test]
+    fn tape_limit_in_multiply_add() {
+        // the second target is past the limit, so the first one isn't added to yet either
+        let code = "++[->+>>>>>+<<<<<<]";
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Infinite, EdgePolicy::Wrap).unwrap(),
+            limits: Limits { max_tape: Some(3), ..Default::default() },
+            ..Default::default()
+        };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let res = interpreter.execute(optimize(&program(code)));
+        assert!(matches!(res, Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Tape, .. }))));
+        assert_eq!(interpreter.tape().nonzero_cells(), [(0, 2)]);
+
+        interpreter.limits_mut().max_tape = None;
+        interpreter.resume().unwrap();
+        assert_eq!(interpreter.tape().nonzero_cells(), [(1, 2), (6, 2)]);
+    }
+
+    #[test]
+    fn deadline() {
+        let mut output = Vec::new();
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let mut interpreter = limited(Limits { deadline: Some(deadline), ..Default::default() }, &mut output);
+        let res = interpreter.execute(program("+[]"));
+        assert!(matches!(res, Err(RuntimeError::Limit(LimitExceeded { limit: Limit::Deadline, .. }))));
+        assert!(Instant::now() >= deadline);
+    }
+}
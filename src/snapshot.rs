@@ -0,0 +1,414 @@
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use crate::cell::{Cell, CellWidth};
+use crate::desugared_brainfuck::{DesugaredBrainFuckInstruction, DesugaredBrainFuckProgram, ProgramCounter};
+use crate::tape::{EdgePolicy, Tape, TapeConfig, TapeKind};
+
+const MAGIC: &[u8; 8] = b"BFSNAP\0\0";
+/// The version of the snapshot format that [`Snapshot::write_to`] writes.
+pub const VERSION: u16 = 1;
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    /// the data doesn't start like a snapshot
+    NotASnapshot,
+    /// the snapshot was written in a newer format
+    UnsupportedVersion(u16),
+    /// the snapshot was taken with cells of a different width, in bits
+    CellWidth { expected: CellWidth, found: u8 },
+    /// the snapshot is damaged
+    Corrupt(&'static str),
+    /// the snapshot stopped in a different program than the one it's restored with
+    ProgramMismatch,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "couldn't read or write the snapshot: {e}"),
+            SnapshotError::NotASnapshot => write!(f, "not a snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "snapshot version {v} isn't supported, only versions up to {VERSION}"),
+            SnapshotError::CellWidth { expected, found } => write!(f, "the snapshot has {found} bit cells, expected {expected} bit cells"),
+            SnapshotError::Corrupt(what) => write!(f, "the snapshot is damaged: {what}"),
+            SnapshotError::ProgramMismatch => write!(f, "the snapshot was taken while running a different program"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SnapshotError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => SnapshotError::Corrupt("it ends too early"),
+            _ => SnapshotError::Io(e),
+        }
+    }
+}
+
+/// The complete state of an interpreter, taken with
+/// [`BrainFuckInterpreter::snapshot`](crate::BrainFuckInterpreter::snapshot). A program that
+/// was stopped by a limit or an error can continue from it, even in another process.
+///
+/// The program itself isn't part of the snapshot, only a fingerprint to check that it's restored
+/// with the same one.
+#[derive(Clone)]
+pub struct Snapshot<C: Cell = u8> {
+    pub(crate) tape: Tape<C>,
+    /// input that was read but not used yet
+    pub(crate) input: Vec<u8>,
+    /// the fingerprint of the paused program, and where it continues
+    pub(crate) paused: Option<(u64, ProgramCounter)>,
+    pub(crate) executed: u64,
+    pub(crate) written: u64,
+}
+
+impl<C: Cell> Snapshot<C> {
+    pub fn tape(&self) -> &Tape<C> {
+        &self.tape
+    }
+
+    /// Input that was read but not used by the program yet. It's used before any new input.
+    pub fn pending_input(&self) -> &[u8] {
+        &self.input
+    }
+
+    /// Where the paused program continues, if there was one.
+    pub fn pc(&self) -> Option<&ProgramCounter> {
+        self.paused.as_ref().map(|(_, pc)| pc)
+    }
+
+    /// How many instructions ran before the snapshot.
+    pub fn instructions(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn write_to(&self, mut w: impl Write) -> std::io::Result<()> {
+        w.write_all(MAGIC)?;
+        w.write_all(&VERSION.to_le_bytes())?;
+        w.write_all(&[C::WIDTH.bits() as u8])?;
+
+        let config = self.tape.config();
+        let (kind, len) = match config.kind() {
+            TapeKind::Fixed(n) => (0, n),
+            TapeKind::GrowRight(n) => (1, n),
+            TapeKind::Infinite => (2, 0),
+            TapeKind::Sparse(n) => (3, n),
+        };
+        let edges = match config.edges() {
+            EdgePolicy::Wrap => 0,
+            EdgePolicy::Error => 1,
+            EdgePolicy::Grow => 2,
+        };
+        w.write_all(&[kind, edges])?;
+        write_u64(&mut w, len as u64)?;
+
+        let (start, end) = self.tape.bounds();
+        write_i64(&mut w, start as i64)?;
+        write_i64(&mut w, end as i64)?;
+        write_i64(&mut w, self.tape.ptr() as i64)?;
+        let cells = self.tape.nonzero_cells();
+        write_u64(&mut w, cells.len() as u64)?;
+        for (position, value) in cells {
+            write_i64(&mut w, position as i64)?;
+            w.write_all(&value.to_u32().to_le_bytes()[..C::WIDTH.bits() as usize / 8])?;
+        }
+
+        write_u64(&mut w, self.input.len() as u64)?;
+        w.write_all(&self.input)?;
+        write_u64(&mut w, self.executed)?;
+        write_u64(&mut w, self.written)?;
+
+        match &self.paused {
+            Some((program, pc)) => {
+                w.write_all(&[1])?;
+                write_u64(&mut w, *program)?;
+                write_u64(&mut w, pc.path().len() as u64)?;
+                for idx in pc.path() {
+                    write_u64(&mut w, *idx as u64)?;
+                }
+            }
+            None => w.write_all(&[0])?,
+        }
+
+        w.flush()
+    }
+
+    pub fn read_from(mut r: impl Read) -> Result<Self, SnapshotError> {
+        let mut magic = [0; 8];
+        r.read_exact(&mut magic).map_err(|_| SnapshotError::NotASnapshot)?;
+        if &magic != MAGIC {
+            return Err(SnapshotError::NotASnapshot);
+        }
+        let version = u16::from_le_bytes(read_array(&mut r)?);
+        if version > VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let [bits] = read_array(&mut r)?;
+        if bits as u32 != C::WIDTH.bits() {
+            return Err(SnapshotError::CellWidth { expected: C::WIDTH, found: bits });
+        }
+
+        let [kind, edges] = read_array(&mut r)?;
+        let len = read_usize(&mut r)?;
+        let kind = match kind {
+            0 => TapeKind::Fixed(len),
+            1 => TapeKind::GrowRight(len),
+            2 => TapeKind::Infinite,
+            3 => TapeKind::Sparse(len),
+            _ => return Err(SnapshotError::Corrupt("unknown kind of tape")),
+        };
+        let edges = match edges {
+            0 => EdgePolicy::Wrap,
+            1 => EdgePolicy::Error,
+            2 => EdgePolicy::Grow,
+            _ => return Err(SnapshotError::Corrupt("unknown edge policy")),
+        };
+
+        let start = read_isize(&mut r)?;
+        let end = read_isize(&mut r)?;
+        let ptr = read_isize(&mut r)?;
+        let count = read_usize(&mut r)?;
+        let mut cells = Vec::new();
+        for _ in 0..count {
+            let position = read_isize(&mut r)?;
+            let mut value = [0; 4];
+            r.read_exact(&mut value[..C::WIDTH.bits() as usize / 8])?;
+            cells.push((position, C::from_u32(u32::from_le_bytes(value))));
+        }
+        let config = TapeConfig::new(kind, edges).map_err(|_| SnapshotError::Corrupt("the tape has no cells"))?;
+        let tape = Tape::from_parts(config, (start, end), ptr, &cells)
+            .map_err(SnapshotError::Corrupt)?;
+
+        let mut input = Vec::new();
+        let len = read_u64(&mut r)?;
+        r.by_ref().take(len).read_to_end(&mut input)?;
+        if input.len() as u64 != len {
+            return Err(SnapshotError::Corrupt("it ends too early"));
+        }
+        let executed = read_u64(&mut r)?;
+        let written = read_u64(&mut r)?;
+
+        let paused = match read_array(&mut r)? {
+            [0] => None,
+            [1] => {
+                let program = read_u64(&mut r)?;
+                let depth = read_usize(&mut r)?;
+                let path = (0..depth).map(|_| read_usize(&mut r)).collect::<Result<_, _>>()?;
+                Some((program, ProgramCounter::new(path)))
+            }
+            _ => return Err(SnapshotError::Corrupt("invalid program counter")),
+        };
+
+        Ok(Self {
+            tape,
+            input,
+            paused,
+            executed,
+            written,
+        })
+    }
+}
+
+/// Identifies a program, to check that a snapshot is restored with the program it was taken of.
+pub(crate) fn fingerprint(program: &DesugaredBrainFuckProgram) -> u64 {
+    // FNV-1a, which unlike the std hashers is the same in every process
+    fn hash_block(hash: &mut u64, block: &[DesugaredBrainFuckInstruction]) {
+        for instruction in block {
+            for byte in instruction.name().bytes().chain([b';']) {
+                *hash = (*hash ^ byte as u64).wrapping_mul(0x100000001b3);
+            }
+            if let DesugaredBrainFuckInstruction::Loop(body) = instruction {
+                hash_block(hash, body);
+                *hash = (*hash ^ b']' as u64).wrapping_mul(0x100000001b3);
+            }
+        }
+    }
+
+    let mut hash = 0xcbf29ce484222325;
+    hash_block(&mut hash, program.as_slice());
+    hash
+}
+
+/// Whether the program can continue at `pc`.
+pub(crate) fn points_into(program: &DesugaredBrainFuckProgram, pc: &ProgramCounter) -> bool {
+    let mut block = program.as_slice();
+    let Some((last, loops)) = pc.path().split_last() else {
+        return false;
+    };
+    for idx in loops {
+        match block.get(*idx) {
+            Some(DesugaredBrainFuckInstruction::Loop(body)) => block = body,
+            _ => return false,
+        }
+    }
+    // the end of a loop body is where the loop checks its condition again
+    *last < block.len() || (*last == block.len() && !loops.is_empty())
+}
+
+fn read_array<const N: usize>(r: &mut impl Read) -> Result<[u8; N], SnapshotError> {
+    let mut res = [0; N];
+    r.read_exact(&mut res)?;
+    Ok(res)
+}
+
+fn read_u64(r: &mut impl Read) -> Result<u64, SnapshotError> {
+    Ok(u64::from_le_bytes(read_array(r)?))
+}
+
+fn read_usize(r: &mut impl Read) -> Result<usize, SnapshotError> {
+    usize::try_from(read_u64(r)?).map_err(|_| SnapshotError::Corrupt("a number is too large"))
+}
+
+fn read_isize(r: &mut impl Read) -> Result<isize, SnapshotError> {
+    isize::try_from(i64::from_le_bytes(read_array(r)?)).map_err(|_| SnapshotError::Corrupt("a number is too large"))
+}
+
+fn write_u64(w: &mut impl Write, value: u64) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+fn write_i64(w: &mut impl Write, value: i64) -> std::io::Result<()> {
+    w.write_all(&value.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::desugared_brainfuck::DesugaredBrainFuckProgram;
+    use crate::interpreter::{BrainFuckInterpreter, EofPolicy, InterpreterConfig, RuntimeError};
+    use crate::sandbox::Limits;
+    use crate::snapshot::{Snapshot, SnapshotError};
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    fn round_trip<C: crate::cell::Cell>(snapshot: &Snapshot<C>) -> Snapshot<C> {
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        Snapshot::read_from(&bytes[..]).unwrap()
+    }
+
+    #[test]
+    fn checkpoint() {
+        let code = "++++++++[>++++++++<-]>+.+.+.";
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Infinite, EdgePolicy::Wrap).unwrap(),
+            limits: Limits { fuel: Some(30), ..Default::default() },
+            ..Default::default()
+        };
+        let mut output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::with_config(&mut output, empty(), config);
+        assert!(interpreter.execute(program(code)).is_err());
+        let snapshot = round_trip(&interpreter.snapshot());
+        assert_eq!(snapshot.pc(), interpreter.paused_at());
+        assert_eq!(snapshot.tape().bounds(), interpreter.tape().bounds());
+        drop(interpreter);
+
+        // continue in a fresh interpreter, without a fuel limit
+        let mut restored_output = Vec::new();
+        let mut restored = BrainFuckInterpreter::new(&mut restored_output, empty());
+        restored.restore(snapshot, program(code)).unwrap();
+        assert_eq!(restored.resume().unwrap().instructions, 1 + 9 + 8 * 4 + 7);
+        drop(restored);
+        assert_eq!(restored_output, b"ABC");
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn warm_start() {
+        // builds a table, then stops at the first `,` because there is no input yet
+        let code = "+++[>++<-],[>+<-]>.";
+        let config = InterpreterConfig { eof: EofPolicy::Error, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u16>::with_config(Vec::new(), empty(), config);
+        assert!(matches!(interpreter.execute(program(code)), Err(RuntimeError::EndOfInput)));
+        let snapshot = round_trip(&interpreter.snapshot());
+
+        let mut output = Vec::new();
+        let mut restored = BrainFuckInterpreter::<_, _, u16>::with_config(&mut output, &b"A"[..], config);
+        restored.restore(snapshot.clone(), program(code)).unwrap();
+        restored.resume().unwrap();
+        drop(restored);
+        assert_eq!(output, b"G");
+
+        let mut restored = BrainFuckInterpreter::<_, _, u16>::with_config(Vec::new(), empty(), config);
+        assert!(matches!(restored.restore(snapshot.clone(), program("+[,]")), Err(SnapshotError::ProgramMismatch)));
+
+        let mut bytes = Vec::new();
+        snapshot.write_to(&mut bytes).unwrap();
+        assert!(matches!(Snapshot::<u8>::read_from(&bytes[..]), Err(SnapshotError::CellWidth { found: 16, .. })));
+        assert!(matches!(Snapshot::<u16>::read_from(&bytes[..bytes.len() - 3]), Err(SnapshotError::Corrupt(_))));
+        assert!(matches!(Snapshot::<u16>::read_from(&b"hello"[..]), Err(SnapshotError::NotASnapshot)));
+    }
+
+    #[test]
+    fn pending_input() {
+        let config = InterpreterConfig { limits: Limits { fuel: Some(2), ..Default::default() }, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::with_config(Vec::new(), &b"xyz"[..], config);
+        assert!(interpreter.execute(program(",.,.,.")).is_err());
+        let snapshot = round_trip(&interpreter.snapshot());
+        assert_eq!(snapshot.pending_input(), b"yz");
+
+        let mut output = Vec::new();
+        let mut restored = BrainFuckInterpreter::new(&mut output, &b"!"[..]);
+        restored.restore(snapshot, program(",.,.,.")).unwrap();
+        restored.resume().unwrap();
+        drop(restored);
+        assert_eq!(output, b"yz");
+
+        // restoring into the interpreter the snapshot came from
+        let mut output = Vec::new();
+        let mut interpreter = BrainFuckInterpreter::with_config(&mut output, &b"xyz"[..], config);
+        assert!(interpreter.execute(program(",[.,]")).is_err());
+        let snapshot: Snapshot = interpreter.snapshot();
+        interpreter.limits_mut().fuel = None;
+        interpreter.restore(snapshot, program(",[.,]")).unwrap();
+        interpreter.resume().unwrap();
+        drop(interpreter);
+        assert_eq!(output, b"xyz");
+    }
+
+    #[test]
+    fn damaged_tape() {
+        // the header is 21 bytes, then come the start and the end of the tape
+        fn with_bounds(snapshot: &Snapshot, start: i64, end: i64) -> Result<Snapshot, SnapshotError> {
+            let mut bytes = Vec::new();
+            snapshot.write_to(&mut bytes).unwrap();
+            bytes[21..29].copy_from_slice(&start.to_le_bytes());
+            bytes[29..37].copy_from_slice(&end.to_le_bytes());
+            Snapshot::read_from(&bytes[..])
+        }
+
+        let mut interpreter = BrainFuckInterpreter::new(Vec::new(), empty());
+        interpreter.execute(program("+>+")).unwrap();
+        let fixed = interpreter.snapshot();
+        assert_eq!(with_bounds(&fixed, 0, 30_000).unwrap().tape().bounds(), (0, 30_000));
+        assert!(matches!(with_bounds(&fixed, 0, 30_003), Err(SnapshotError::Corrupt(_))));
+        assert!(matches!(with_bounds(&fixed, -3, 30_000), Err(SnapshotError::Corrupt(_))));
+
+        let config = InterpreterConfig {
+            tape: TapeConfig::new(TapeKind::Infinite, EdgePolicy::Wrap).unwrap(),
+            ..Default::default()
+        };
+        let mut interpreter = BrainFuckInterpreter::with_config(Vec::new(), empty(), config);
+        interpreter.execute(program("+<+")).unwrap();
+        let infinite = interpreter.snapshot();
+        assert_eq!(with_bounds(&infinite, -5, 8).unwrap().tape().bounds(), (-5, 8));
+        assert!(matches!(with_bounds(&infinite, i64::MIN, 1), Err(SnapshotError::Corrupt(_))));
+        assert!(matches!(with_bounds(&infinite, -1, i64::MAX), Err(SnapshotError::Corrupt(_))));
+        assert!(matches!(with_bounds(&infinite, 0, -1), Err(SnapshotError::Corrupt(_))));
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::{Display, Formatter};
+
+/// A location in a source file. Lines and columns start at 1, columns count characters.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct SourcePosition {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for SourcePosition {
+    fn default() -> Self {
+        Self::start()
+    }
+}
+
+impl SourcePosition {
+    pub fn start() -> Self {
+        Self {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+
+    /// The position right after `c`, if `c` is at this position.
+    pub fn advance(self, c: char) -> Self {
+        if c == '\n' {
+            Self {
+                offset: self.offset + c.len_utf8(),
+                line: self.line + 1,
+                column: 1,
+            }
+        } else {
+            Self {
+                offset: self.offset + c.len_utf8(),
+                line: self.line,
+                column: self.column + 1,
+            }
+        }
+    }
+}
+
+impl Display for SourcePosition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
+/// The part of a source file between two positions, `end` is exclusive.
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Span {
+    pub start: SourcePosition,
+    pub end: SourcePosition,
+}
+
+impl Span {
+    pub fn new(start: SourcePosition, end: SourcePosition) -> Self {
+        Self {
+            start,
+            end,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.end.offset - self.start.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// The source line around a position, with a marker under the interesting part. Owns its text so
+/// errors can carry it around after the source is gone.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Snippet {
+    line: usize,
+    text: String,
+    column: usize,
+    width: usize,
+}
+
+impl Snippet {
+    /// Marks `width` characters starting at `position`, or the end of the line if it's shorter.
+    pub fn new(source: &str, position: SourcePosition, width: usize) -> Self {
+        let start = source[..position.offset.min(source.len())]
+            .rfind('\n')
+            .map_or(0, |i| i + 1);
+        let text = source[start..].lines().next().unwrap_or("");
+
+        Self {
+            line: position.line,
+            text: text.to_string(),
+            column: position.column,
+            width: width.max(1),
+        }
+    }
+}
+
+impl Display for Snippet {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let gutter = self.line.to_string().len();
+        writeln!(f, "{:gutter$} |", "")?;
+        writeln!(f, "{} | {}", self.line, self.text)?;
+
+        // keep tabs so the marker lines up with the text above it
+        let indent: String = self.text.chars()
+            .take(self.column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let available = self.text.chars().count().saturating_sub(self.column - 1);
+        let width = self.width.min(available.max(1));
+        write!(f, "{:gutter$} | {indent}{}", "", "^".repeat(width))
+    }
+}
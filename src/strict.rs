@@ -0,0 +1,118 @@
+use std::fmt::{Display, Formatter};
+use crate::desugared_brainfuck::ProgramCounter;
+use crate::source::Span;
+
+/// What a program did that strict mode doesn't allow.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TrapKind {
+    /// the data pointer would have moved to `position`, left of the start of the tape or past an
+    /// end that doesn't grow
+    PointerOutOfBounds { position: isize },
+    /// adding to a cell would have wrapped past `max`
+    CellOverflow { value: u32, added: usize, max: u32 },
+    /// subtracting from a cell would have wrapped below zero
+    CellUnderflow { value: u32, subtracted: usize },
+}
+
+impl Display for TrapKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TrapKind::PointerOutOfBounds { position } => write!(f, "the data pointer moved off the tape, to {position}"),
+            TrapKind::CellOverflow { value, added, max } => write!(f, "cell overflow, {value} + {added} is larger than {max}"),
+            TrapKind::CellUnderflow { value, subtracted } => write!(f, "cell underflow, {value} - {subtracted} is below zero"),
+        }
+    }
+}
+
+/// A program running in [strict mode](crate::interpreter::InterpreterConfig::strict) moved the
+/// data pointer off the tape, or made a cell wrap around. The instruction that did it didn't run.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Trap {
+    pub kind: TrapKind,
+    pub pc: ProgramCounter,
+    /// where the instruction is in the source, if the program has a source map
+    pub span: Option<Span>,
+    pub ptr: isize,
+    /// the cells around the data pointer, like [`Tape::window`](crate::tape::Tape::window) shows them
+    pub tape: String,
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.kind)?;
+        match self.span {
+            Some(span) => write!(f, " at {} (instruction {})", span.start, self.pc)?,
+            None => write!(f, " at instruction {}", self.pc)?,
+        }
+        write!(f, ", with the data pointer at {}\n{}", self.ptr, self.tape)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+#[cfg(test)]
+mod tests {
+    use std::io::empty;
+    use crate::brainfuck::BrainFuckProgram;
+    use crate::bytecode::Bytecode;
+    use crate::desugared_brainfuck::{DesugaredBrainFuckProgram, ProgramCounter};
+    use crate::interpreter::{BrainFuckInterpreter, InterpreterConfig, RuntimeError};
+    use crate::low_intermediate::LowLevelIntermediateProgram;
+    use crate::strict::{Trap, TrapKind};
+    use crate::tape::{EdgePolicy, TapeConfig, TapeKind};
+
+    fn program(code: &str) -> DesugaredBrainFuckProgram {
+        let program: BrainFuckProgram = code.parse().unwrap();
+        program.desugar().unwrap()
+    }
+
+    // runs a program in strict mode with both engines, which have to trap in the same way
+    fn run(code: &str, tape: TapeConfig) -> Trap {
+        let config = InterpreterConfig { tape, strict: true, ..Default::default() };
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let Err(RuntimeError::Trap(bytecode)) = interpreter.execute_bytecode(&Bytecode::compile(&program(code))) else {
+            panic!("'{code}' should trap");
+        };
+
+        let mut interpreter = BrainFuckInterpreter::<_, _, u8>::with_config(Vec::new(), empty(), config);
+        let Err(RuntimeError::Trap(tree)) = interpreter.execute(program(code)) else {
+            panic!("'{code}' should trap");
+        };
+        assert_eq!(bytecode, tree);
+        *tree
+    }
+
+    #[test]
+    fn pointer() {
+        let trap = run("+>\n<<", TapeConfig::default());
+        assert_eq!(trap.kind, TrapKind::PointerOutOfBounds { position: -1 });
+        assert_eq!(trap.pc, ProgramCounter::new(vec![2]));
+        assert_eq!(trap.span.map(|i| (i.start.line, i.start.column)), Some((2, 1)));
+        assert!(trap.to_string().starts_with("the data pointer moved off the tape, to -1 at line 2, column 1 (instruction 2)"), "{trap}");
+
+        // growing tapes may still grow to the right, but not to the left of the start
+        let infinite = TapeConfig::new(TapeKind::Infinite, EdgePolicy::Wrap).unwrap();
+        assert_eq!(run(">>>>[-]<<<<<", infinite).kind, TrapKind::PointerOutOfBounds { position: -1 });
+        let small = TapeConfig::new(TapeKind::Fixed(3), EdgePolicy::Wrap).unwrap();
+        assert_eq!(run(">>>", small).kind, TrapKind::PointerOutOfBounds { position: 3 });
+    }
+
+    #[test]
+    fn cells() {
+        let trap = run("++++++++[>++++++++<-]>[>++++<-]", TapeConfig::default());
+        assert_eq!(trap.kind, TrapKind::CellOverflow { value: 252, added: 4, max: 255 });
+        assert_eq!(trap.ptr, 2);
+
+        assert_eq!(run("+--", TapeConfig::default()).kind, TrapKind::CellUnderflow { value: 1, subtracted: 2 });
+    }
+
+    #[test]
+    fn lil_doesnt_wrap() {
+        let code = "one = 1;\na = 5;\nb = 3;\nc = 0;\nwhile a != 0 {\n    c += b;\n    c -= one;\n    a -= one;\n}\nprint c;";
+        let program = LowLevelIntermediateProgram::parse(code).unwrap().compile();
+        let config = InterpreterConfig { strict: true, ..Default::default() };
+        let mut output = Vec::new();
+        BrainFuckInterpreter::<_, _, u8>::with_config(&mut output, empty(), config).execute(program).unwrap();
+        assert_eq!(output, [10]);
+    }
+}
@@ -0,0 +1,552 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use crate::cell::Cell;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum TapeKind {
+    /// `n` cells, allocated up front
+    Fixed(usize),
+    /// starts out with `n` cells, and always grows when the pointer moves past the right end
+    GrowRight(usize),
+    /// grows in both directions, cells left of the start have negative positions
+    Infinite,
+    /// `n` cells, of which only the ones that were written take up memory
+    Sparse(usize),
+}
+
+/// What happens when the data pointer moves past an end of the tape that doesn't grow by itself.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum EdgePolicy {
+    /// continue from the other end of the tape
+    Wrap,
+    /// stop with a runtime error
+    Error,
+    /// extend the tape up to the pointer
+    Grow,
+}
+
+/// How a tape is laid out. It always has at least one cell, so it's only made with
+/// [`TapeConfig::new`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TapeConfig {
+    kind: TapeKind,
+    edges: EdgePolicy,
+}
+
+impl Default for TapeConfig {
+    fn default() -> Self {
+        Self {
+            kind: TapeKind::Fixed(30_000),
+            edges: EdgePolicy::Wrap,
+        }
+    }
+}
+
+impl TapeConfig {
+    /// A configuration for a tape with at least one cell.
+    pub fn new(kind: TapeKind, edges: EdgePolicy) -> Result<Self, InvalidTapeConfig> {
+        match kind {
+            TapeKind::Fixed(0) | TapeKind::GrowRight(0) | TapeKind::Sparse(0) => Err(InvalidTapeConfig("the tape needs at least one cell".to_string())),
+            _ => Ok(Self { kind, edges }),
+        }
+    }
+
+    pub fn kind(&self) -> TapeKind {
+        self.kind
+    }
+
+    pub fn edges(&self) -> EdgePolicy {
+        self.edges
+    }
+}
+
+#[derive(Debug)]
+pub struct InvalidTapeConfig(String);
+
+impl Display for InvalidTapeConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTapeConfig {}
+
+impl FromStr for TapeKind {
+    type Err = InvalidTapeConfig;
+
+    /// Parses `fixed:N`, `grow-right`, `grow-right:N`, `infinite` or `sparse:N`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, size) = match s.split_once(':') {
+            Some((name, size)) => {
+                let size = size.parse()
+                    .map_err(|_| InvalidTapeConfig(format!("invalid tape size '{size}'")))?;
+                (name, Some(size))
+            }
+            None => (s, None),
+        };
+
+        match (name, size) {
+            ("fixed", Some(n)) | ("sparse", Some(n)) if n == 0 => Err(InvalidTapeConfig("the tape needs at least one cell".to_string())),
+            ("fixed", Some(n)) => Ok(TapeKind::Fixed(n)),
+            ("grow-right", n) => Ok(TapeKind::GrowRight(n.unwrap_or(1).max(1))),
+            ("infinite", None) => Ok(TapeKind::Infinite),
+            ("sparse", Some(n)) => Ok(TapeKind::Sparse(n)),
+            _ => Err(InvalidTapeConfig(format!("invalid tape '{s}', expected fixed:N, grow-right[:N], infinite or sparse:N"))),
+        }
+    }
+}
+
+impl FromStr for EdgePolicy {
+    type Err = InvalidTapeConfig;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "wrap" => Ok(EdgePolicy::Wrap),
+            "error" => Ok(EdgePolicy::Error),
+            "grow" => Ok(EdgePolicy::Grow),
+            _ => Err(InvalidTapeConfig(format!("invalid edge policy '{s}', expected wrap, error or grow"))),
+        }
+    }
+}
+
+/// The data pointer tried to leave a tape with [`EdgePolicy::Error`].
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct TapeError {
+    /// where the pointer would have ended up
+    pub position: isize,
+    /// the positions on the tape at that time, `start..end`
+    pub start: isize,
+    pub end: isize,
+}
+
+impl Display for TapeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "data pointer moved to {}, outside of the tape ({}..{})", self.position, self.start, self.end)
+    }
+}
+
+impl std::error::Error for TapeError {}
+
+// the most cells a dense tape restored from a snapshot may have, so a damaged snapshot can't
+// make it allocate all memory
+const MAX_RESTORED_CELLS: usize = 1 << 30;
+
+#[derive(Clone)]
+enum Storage<C> {
+    Dense(Vec<C>),
+    Sparse(HashMap<isize, C>),
+}
+
+#[derive(Clone)]
+pub struct Tape<C: Cell = u8> {
+    config: TapeConfig,
+    storage: Storage<C>,
+    // the positions currently on the tape are `start..end`. For dense storage, position
+    // `start` is at index 0.
+    start: isize,
+    end: isize,
+    ptr: isize,
+}
+
+impl<C: Cell> Tape<C> {
+    pub fn new(config: TapeConfig) -> Self {
+        let (storage, len) = match config.kind {
+            TapeKind::Fixed(n) | TapeKind::GrowRight(n) => (Storage::Dense(vec![C::default(); n]), n),
+            TapeKind::Infinite => (Storage::Dense(vec![C::default(); 1]), 1),
+            TapeKind::Sparse(n) => (Storage::Sparse(HashMap::new()), n),
+        };
+
+        Self {
+            config,
+            storage,
+            start: 0,
+            end: len as isize,
+            ptr: 0,
+        }
+    }
+
+    pub fn config(&self) -> TapeConfig {
+        self.config
+    }
+
+    pub fn ptr(&self) -> isize {
+        self.ptr
+    }
+
+    /// The positions currently on the tape. Growing tapes only include what was reached so far.
+    pub fn bounds(&self) -> (isize, isize) {
+        (self.start, self.end)
+    }
+
+    pub fn get(&self) -> C {
+        match &self.storage {
+            Storage::Dense(cells) => cells[(self.ptr - self.start) as usize],
+            Storage::Sparse(cells) => cells.get(&self.ptr).copied().unwrap_or_default(),
+        }
+    }
+
+    pub fn set(&mut self, value: C) {
+        self.set_at(self.ptr, value);
+    }
+
+    // sets the cell at a position on the tape
+    pub(crate) fn set_at(&mut self, position: isize, value: C) {
+        match &mut self.storage {
+            Storage::Dense(cells) => cells[(position - self.start) as usize] = value,
+            Storage::Sparse(cells) => {
+                if value.is_zero() {
+                    cells.remove(&position);
+                } else {
+                    cells.insert(position, value);
+                }
+            }
+        }
+    }
+
+    /// The cell at any position, or `None` if it isn't on the tape (yet).
+    pub fn get_at(&self, position: isize) -> Option<C> {
+        if position < self.start || position >= self.end {
+            return None;
+        }
+
+        Some(match &self.storage {
+            Storage::Dense(cells) => cells[(position - self.start) as usize],
+            Storage::Sparse(cells) => cells.get(&position).copied().unwrap_or_default(),
+        })
+    }
+
+    /// Shows the cells within `radius` of the data pointer.
+    pub fn window(&self, radius: usize) -> Window<'_, C> {
+        Window {
+            tape: self,
+            radius: isize::try_from(radius).unwrap_or(isize::MAX),
+        }
+    }
+
+    // the cells that aren't zero, in order, for snapshots
+    pub(crate) fn nonzero_cells(&self) -> Vec<(isize, C)> {
+        let mut res: Vec<_> = match &self.storage {
+            Storage::Dense(cells) => cells.iter()
+                .enumerate()
+                .filter(|(_, value)| !value.is_zero())
+                .map(|(idx, value)| (self.start + idx as isize, *value))
+                .collect(),
+            Storage::Sparse(cells) => cells.iter().map(|(position, value)| (*position, *value)).collect(),
+        };
+        res.sort_by_key(|(position, _)| *position);
+        res
+    }
+
+    // a tape that spans `start..end`, or what's wrong with the parts if they can't belong to a
+    // tape with this configuration
+    pub(crate) fn from_parts(config: TapeConfig, (start, end): (isize, isize), ptr: isize, cells: &[(isize, C)]) -> Result<Self, &'static str> {
+        let extent = end.checked_sub(start)
+            .and_then(|extent| usize::try_from(extent).ok())
+            .ok_or("the tape ends before it starts")?;
+        // tapes only ever grow from where they started, and only at the ends that can grow
+        let (len, grows_left, grows_right) = match config.kind {
+            TapeKind::Fixed(n) | TapeKind::Sparse(n) => (n, false, false),
+            TapeKind::GrowRight(n) => (n, false, true),
+            TapeKind::Infinite => (1, true, true),
+        };
+        let grows = config.edges == EdgePolicy::Grow;
+        let len = isize::try_from(len).map_err(|_| "the tape is too large")?;
+        let fits_left = if grows_left || grows { start <= 0 } else { start == 0 };
+        let fits_right = if grows_right || grows { end >= len } else { end == len };
+        if !fits_left || !fits_right {
+            return Err("the tape doesn't have the size of its kind");
+        }
+        if !matches!(config.kind, TapeKind::Sparse(_)) && extent > MAX_RESTORED_CELLS {
+            return Err("the tape is too large");
+        }
+        let inside = |position: isize| position >= start && position < end;
+        if !inside(ptr) || !cells.iter().all(|(position, _)| inside(*position)) {
+            return Err("the cells don't fit on the tape");
+        }
+
+        let storage = match config.kind {
+            TapeKind::Sparse(_) => Storage::Sparse(HashMap::new()),
+            _ => Storage::Dense(vec![C::default(); extent]),
+        };
+        let mut tape = Self {
+            config,
+            storage,
+            start,
+            end,
+            ptr,
+        };
+        for (position, value) in cells {
+            tape.ptr = *position;
+            tape.set(*value);
+        }
+        tape.ptr = ptr;
+        Ok(tape)
+    }
+
+    // what happens when the pointer moves to `target`, which isn't on the tape
+    fn policy_at(&self, target: isize) -> EdgePolicy {
+        match (self.config.kind, target < self.start) {
+            (TapeKind::Infinite, _) | (TapeKind::GrowRight(_), false) => EdgePolicy::Grow,
+            _ => self.config.edges,
+        }
+    }
+
+    /// How many cells the tape would span after moving the pointer by `delta`.
+    pub fn extent_after_move(&self, delta: isize) -> usize {
+        let target = self.ptr.saturating_add(delta);
+        let (start, end) = if (target < self.start || target >= self.end) && self.policy_at(target) == EdgePolicy::Grow {
+            self.grown_bounds(target)
+        } else {
+            (self.start, self.end)
+        };
+        (end - start) as usize
+    }
+
+    // where moving the pointer by `delta` would take it, if that's left of position 0 or past
+    // an end of the tape that doesn't grow
+    pub(crate) fn leaves_tape(&self, delta: isize) -> Option<isize> {
+        let target = self.ptr.saturating_add(delta);
+        let outside = target < self.start || target >= self.end;
+        (target < 0 || outside && self.policy_at(target) != EdgePolicy::Grow).then_some(target)
+    }
+
+    // moves the pointer `stride` cells at a time until it's on a zero cell, or on the last cell
+    // before it would move off the tape. Returns whether it found a zero.
+    pub(crate) fn scan(&mut self, stride: isize) -> bool {
+        let step = stride.unsigned_abs();
+        // the cells from the pointer up to the edge it moves towards
+        let ahead = if stride > 0 { self.end - self.ptr } else { self.ptr - self.start + 1 } as usize;
+        let distance = match &self.storage {
+            Storage::Dense(cells) => {
+                let idx = (self.ptr - self.start) as usize;
+                match stride {
+                    1 => C::find_zero(&cells[idx..]),
+                    -1 => C::rfind_zero(&cells[..=idx]).map(|i| idx - i),
+                    _ if stride > 0 => cells[idx..].iter().step_by(step).position(|i| i.is_zero()).map(|i| i * step),
+                    _ => cells[..=idx].iter().rev().step_by(step).position(|i| i.is_zero()).map(|i| i * step),
+                }
+            }
+            // zeros aren't stored
+            Storage::Sparse(cells) => (0..ahead).step_by(step).find(|i| {
+                let position = if stride > 0 { self.ptr + *i as isize } else { self.ptr - *i as isize };
+                !cells.contains_key(&position)
+            }),
+        };
+
+        let found = distance.is_some();
+        let distance = distance.unwrap_or((ahead - 1) / step * step) as isize;
+        self.ptr += if stride > 0 { distance } else { -distance };
+        found
+    }
+
+    pub fn move_by(&mut self, delta: isize) -> Result<(), TapeError> {
+        // staying on the tape is by far the most common move, so it's checked first
+        let target = self.ptr.saturating_add(delta);
+        self.ptr = if target >= self.start && target < self.end { target } else { self.reach(delta)? };
+        Ok(())
+    }
+
+    // the position moving the pointer by `delta` would take it to, growing the tape up to there
+    // if it grows, but without moving the pointer
+    pub(crate) fn reach(&mut self, delta: isize) -> Result<isize, TapeError> {
+        let target = self.ptr.saturating_add(delta);
+        if target >= self.start && target < self.end {
+            return Ok(target);
+        }
+
+        match self.policy_at(target) {
+            EdgePolicy::Wrap => Ok(self.start + (target - self.start).rem_euclid(self.end - self.start)),
+            EdgePolicy::Error => Err(TapeError {
+                position: target,
+                start: self.start,
+                end: self.end,
+            }),
+            EdgePolicy::Grow => {
+                self.grow_to(target);
+                Ok(target)
+            }
+        }
+    }
+
+    // the bounds after growing the tape up to `position`
+    fn grown_bounds(&self, position: isize) -> (isize, isize) {
+        match &self.storage {
+            _ if position >= self.end => (self.start, position + 1),
+            // grow by at least the current size, so moving left cell by cell doesn't copy the
+            // whole tape every time
+            Storage::Dense(cells) => (self.start - ((self.start - position) as usize).max(cells.len()) as isize, self.end),
+            Storage::Sparse(_) => (position, self.end),
+        }
+    }
+
+    fn grow_to(&mut self, position: isize) {
+        let (start, end) = self.grown_bounds(position);
+        match &mut self.storage {
+            Storage::Dense(cells) if start == self.start => cells.resize((end - start) as usize, C::default()),
+            Storage::Dense(cells) => {
+                let mut grown = vec![C::default(); (end - start) as usize];
+                grown[(self.start - start) as usize..].copy_from_slice(cells);
+                *cells = grown;
+            }
+            Storage::Sparse(_) => {}
+        }
+        self.start = start;
+        self.end = end;
+    }
+}
+
+/// Displays part of a tape as a row of positions, a row of values and a marker under the data
+/// pointer. Cells that aren't on the tape (yet) are left out.
+pub struct Window<'a, C: Cell> {
+    tape: &'a Tape<C>,
+    radius: isize,
+}
+
+impl<C: Cell> Display for Window<'_, C> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let ptr = self.tape.ptr;
+        // wide enough for the largest value a cell can hold
+        let column = C::WIDTH.max_value().to_string().len().max(5) + 1;
+
+        let mut indices = String::new();
+        let mut values = String::new();
+        let mut marker = String::new();
+        // only the positions on the tape, so a huge radius doesn't take forever
+        let first = ptr.saturating_sub(self.radius).max(self.tape.start);
+        let last = ptr.saturating_add(self.radius).min(self.tape.end - 1);
+        for position in first..=last {
+            let Some(value) = self.tape.get_at(position) else {
+                continue;
+            };
+            indices.push_str(&format!("{position:>column$}"));
+            values.push_str(&format!("{value:>column$}"));
+            marker.push_str(&format!("{:>column$}", if position == ptr { "^" } else { "" }));
+        }
+
+        writeln!(f, "{indices}")?;
+        writeln!(f, "{values}")?;
+        write!(f, "{}", marker.trim_end())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tape::{EdgePolicy, Tape, TapeConfig, TapeError, TapeKind};
+
+    fn tape(kind: TapeKind, edges: EdgePolicy) -> Tape {
+        Tape::new(TapeConfig::new(kind, edges).unwrap())
+    }
+
+    #[test]
+    fn fixed() {
+        let mut t = tape(TapeKind::Fixed(4), EdgePolicy::Wrap);
+        t.move_by(-1).unwrap();
+        assert_eq!(t.ptr(), 3);
+        t.move_by(6).unwrap();
+        assert_eq!(t.ptr(), 1);
+        assert_eq!(t.window(usize::MAX).to_string().lines().nth(1).unwrap().split_whitespace().count(), 4);
+
+        let mut t = tape(TapeKind::Fixed(4), EdgePolicy::Error);
+        assert_eq!(t.move_by(4), Err(TapeError { position: 4, start: 0, end: 4 }));
+        assert_eq!(t.ptr(), 0);
+
+        let mut t = tape(TapeKind::Fixed(4), EdgePolicy::Grow);
+        t.move_by(9).unwrap();
+        t.set(7);
+        assert_eq!(t.bounds(), (0, 10));
+        assert_eq!(t.get_at(9), Some(7));
+    }
+
+    #[test]
+    fn grow_right() {
+        let mut t = tape(TapeKind::GrowRight(1), EdgePolicy::Error);
+        t.move_by(1000).unwrap();
+        assert_eq!(t.bounds(), (0, 1001));
+        assert!(t.move_by(-1001).is_err());
+
+        let mut t = tape(TapeKind::GrowRight(1), EdgePolicy::Wrap);
+        t.move_by(3).unwrap();
+        t.move_by(-5).unwrap();
+        assert_eq!(t.ptr(), 2);
+    }
+
+    #[test]
+    fn infinite() {
+        let mut t = tape(TapeKind::Infinite, EdgePolicy::Error);
+        t.set(1);
+        for i in 1..=100 {
+            t.move_by(-1).unwrap();
+            t.set(i as u8 + 1);
+        }
+        assert_eq!(t.ptr(), -100);
+        for i in 0..=100 {
+            assert_eq!(t.get_at(-i), Some(i as u8 + 1));
+        }
+        t.move_by(200).unwrap();
+        assert_eq!(t.get(), 0);
+    }
+
+    #[test]
+    fn sparse() {
+        let mut t = tape(TapeKind::Sparse(1 << 40), EdgePolicy::Error);
+        t.move_by(1 << 39).unwrap();
+        t.set(5);
+        assert_eq!(t.get_at(1 << 39), Some(5));
+        t.set(0);
+        assert!(t.move_by(1 << 39).is_err());
+
+        let mut t = tape(TapeKind::Sparse(10), EdgePolicy::Wrap);
+        t.move_by(-1).unwrap();
+        assert_eq!(t.ptr(), 9);
+
+        let mut t = tape(TapeKind::Sparse(10), EdgePolicy::Grow);
+        t.move_by(-20).unwrap();
+        assert_eq!(t.bounds(), (-20, 10));
+    }
+
+    #[test]
+    fn scan() {
+        let mut t = tape(TapeKind::Fixed(10), EdgePolicy::Wrap);
+        for i in [1, 2, 3, 4, 6, 7] {
+            t.move_by(i - t.ptr()).unwrap();
+            t.set(1);
+        }
+        t.move_by(-6).unwrap();
+        assert!(t.scan(1));
+        assert_eq!(t.ptr(), 5);
+        assert!(t.scan(-2));
+        assert_eq!(t.ptr(), 5);
+        t.move_by(-1).unwrap();
+        assert!(t.scan(-2));
+        assert_eq!(t.ptr(), 0);
+
+        // stops on the last cell before the edge
+        t.move_by(4).unwrap();
+        assert!(!t.scan(3));
+        assert_eq!(t.ptr(), 7);
+
+        let mut t = Tape::<u16>::new(TapeConfig::new(TapeKind::Sparse(1 << 20), EdgePolicy::Error).unwrap());
+        t.set(1);
+        t.move_by(1).unwrap();
+        t.set(1);
+        assert!(!t.scan(-1));
+        assert_eq!(t.ptr(), 0);
+        assert!(t.scan(1));
+        assert_eq!(t.ptr(), 2);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("fixed:10".parse::<TapeKind>().unwrap(), TapeKind::Fixed(10));
+        assert_eq!("grow-right".parse::<TapeKind>().unwrap(), TapeKind::GrowRight(1));
+        assert_eq!("infinite".parse::<TapeKind>().unwrap(), TapeKind::Infinite);
+        assert_eq!("sparse:5".parse::<TapeKind>().unwrap(), TapeKind::Sparse(5));
+        assert!("fixed".parse::<TapeKind>().is_err());
+        assert!("fixed:0".parse::<TapeKind>().is_err());
+        assert!(TapeConfig::new(TapeKind::Fixed(0), EdgePolicy::Wrap).is_err());
+        assert!(TapeConfig::new(TapeKind::Sparse(0), EdgePolicy::Grow).is_err());
+        assert!(TapeConfig::new(TapeKind::Infinite, EdgePolicy::Error).is_ok());
+        assert_eq!("grow".parse::<EdgePolicy>().unwrap(), EdgePolicy::Grow);
+    }
+}
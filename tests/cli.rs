@@ -0,0 +1,44 @@
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn run(args: &[&str], input: &[u8]) -> std::process::Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_brainfuck-compiler"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn coverage_over_runs() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR")).join("coverage_over_runs");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("double.b");
+    fs::write(&program, ",[>+<-]\n>.").unwrap();
+    let counts = dir.join("counts");
+    let listing = dir.join("listing");
+    let spec = format!("counts={},listing={}", counts.display(), listing.display());
+    let args = ["run", program.to_str().unwrap(), "--coverage", &spec];
+
+    // the first run never enters the loop
+    assert!(run(&args, b"").status.success());
+    assert!(fs::read_to_string(&listing).unwrap().contains('^'));
+
+    assert!(run(&args, b"\x02").status.success());
+    let report = fs::read_to_string(&listing).unwrap();
+    assert!(report.starts_with("        2 |     1 | ,[>+<-]\n"), "{report}");
+    assert!(!report.contains('^'), "{report}");
+
+    // counts of another program can't be added to
+    fs::write(&program, "+.").unwrap();
+    let output = run(&args, b"");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stderr).contains("different program"));
+}
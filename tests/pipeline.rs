@@ -0,0 +1,101 @@
+use std::io::{empty, Cursor};
+use brainfuck_compiler::{BrainFuckInterpreter, BrainFuckProgram, LilSession, LowLevelIntermediateProgram};
+use brainfuck_compiler::brainfuck::UnbalancedLoopKind;
+use brainfuck_compiler::emit::{Artifacts, Stage};
+
+const HELLO_WORLD: &str = "++++++++[>++++[>++>+++>+++>+<<<<-]>+>+>->>+[<]<-]>>.>---.+++++++..+++.>>.<-.<.+++.------.--------.>>+.>++.";
+
+fn run_brainfuck(code: &str, input: &[u8]) -> Vec<u8> {
+    let program: BrainFuckProgram = code.parse().unwrap();
+    let Ok(desugared) = program.desugar() else {
+        panic!("unbalanced loop")
+    };
+
+    let mut output = Vec::new();
+    BrainFuckInterpreter::new(&mut output, input).execute(desugared).unwrap();
+    output
+}
+
+#[test]
+fn hello_world() {
+    assert_eq!(run_brainfuck(HELLO_WORLD, &[]), b"Hello World!\n");
+}
+
+#[test]
+fn echo_input() {
+    assert_eq!(run_brainfuck(",.,.", b"hi"), b"hi");
+}
+
+#[test]
+fn unbalanced_loops() {
+    let program: BrainFuckProgram = "+[[-]\n]]\n [".parse().unwrap();
+    let Err(errors) = program.desugar() else {
+        panic!("loops should be unbalanced")
+    };
+
+    let found: Vec<_> = errors.errors().iter()
+        .map(|i| (i.kind, i.position.map(|p| (p.offset, p.line, p.column))))
+        .collect();
+    assert_eq!(found, [
+        (UnbalancedLoopKind::TooManyClose, Some((7, 2, 2))),
+        (UnbalancedLoopKind::OpenWithoutClose, Some((10, 3, 2))),
+    ]);
+
+    assert_eq!(errors.errors()[1].to_string(), "\
+unbalanced loop: '[' without matching ']' at line 3, column 2
+  |
+3 |  [
+  |  ^");
+}
+
+#[test]
+fn compile_and_run_lil() {
+    let program: LowLevelIntermediateProgram = "
+        counter = 3;
+        one = 1;
+        letter = 65;
+        while counter != 0 {
+            print letter;
+            letter += one;
+            counter -= one;
+        }
+    ".parse().unwrap();
+
+    // the generated brainfuck must behave the same after a round trip through its text form
+    let code = program.compile().resugar().to_string();
+    assert_eq!(run_brainfuck(&code, &[]), b"ABC");
+}
+
+#[test]
+fn lil_syntax_error() {
+    assert!("a = ;".parse::<LowLevelIntermediateProgram>().is_err());
+    assert!("while a != 0 { print a;".parse::<LowLevelIntermediateProgram>().is_err());
+}
+
+#[test]
+fn session_shares_tape() {
+    let mut output = Cursor::new(Vec::new());
+    {
+        let mut interpreter = BrainFuckInterpreter::new(&mut output, empty());
+        let mut session = LilSession::new();
+
+        for line in ["x = 40; y = 2;", "x += y;", "print x;"] {
+            let program = session.parse(line).unwrap();
+            interpreter.execute(session.compile(&program)).unwrap();
+        }
+    }
+
+    assert_eq!(output.into_inner(), [42]);
+}
+
+#[test]
+fn emit_stages() {
+    let artifacts = Artifacts::parse_lil("a = 1; print a;").unwrap();
+    for stage in Stage::ALL {
+        assert!(artifacts.emit(stage).is_some(), "{stage} should be available");
+    }
+
+    let artifacts = Artifacts::from_brainfuck(&"+.".parse().unwrap()).ok().unwrap();
+    assert_eq!(artifacts.emit(Stage::Lil), None);
+    assert_eq!(artifacts.emit(Stage::BrainFuck).unwrap(), "+.\n");
+}